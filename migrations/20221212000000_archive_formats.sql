@@ -0,0 +1,28 @@
+-- Zip and tar archives join 7z and VP as things files can be found inside.
+-- The format columns were free text, so they're now checked against the formats we can read,
+-- to catch a bad value when it's written rather than when it's next read back.
+
+CREATE TABLE IF NOT EXISTS sources_new (
+    `id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    `h_id` INTEGER NOT NULL REFERENCES hashes(id),
+    `path` TEXT NOT NULL, -- path to source of file
+    `location` TEXT NOT NULL,
+    `format` TEXT NOT NULL CHECK (`format` IN ('raw', 'sevenzip', 'vp', 'zip', 'tar')),
+    `size` INTEGER NOT NULL
+);
+INSERT INTO sources_new SELECT `id`, `h_id`, `path`, `location`, `format`, `size` FROM sources;
+DROP TABLE sources;
+ALTER TABLE sources_new RENAME TO sources;
+CREATE INDEX IF NOT EXISTS source_index ON sources(`h_id`);
+
+CREATE TABLE IF NOT EXISTS archive_entries_new(
+    `file_id` INTEGER NOT NULL REFERENCES hashes(id),
+    `archive_id` INTEGER NOT NULL REFERENCES hashes(id),
+    `file_path` TEXT NOT NULL, -- Where to look in archive to get our file.
+    `archive_type` TEXT NOT NULL CHECK (`archive_type` IN ('vp', 'zip', 'sevenzip', 'tar'))
+);
+INSERT INTO archive_entries_new SELECT `file_id`, `archive_id`, `file_path`, `archive_type` FROM archive_entries;
+DROP TABLE archive_entries;
+ALTER TABLE archive_entries_new RENAME TO archive_entries;
+CREATE INDEX IF NOT EXISTS file_index ON archive_entries(`file_id`);
+CREATE INDEX IF NOT EXISTS archive_index ON archive_entries(`archive_id`);
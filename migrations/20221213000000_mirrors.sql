@@ -0,0 +1,10 @@
+-- How downloads from each mirror have gone, so we can pick the best one next time.
+CREATE TABLE IF NOT EXISTS mirrors (
+    `host` TEXT NOT NULL PRIMARY KEY,
+    `successes` INTEGER NOT NULL DEFAULT 0,
+    `failures` INTEGER NOT NULL DEFAULT 0,
+    `bytes` INTEGER NOT NULL DEFAULT 0, -- Total downloaded, including from failed attempts.
+    `throughput` REAL, -- Bytes per second, weighted towards recent downloads.
+    `last_error` TEXT,
+    `last_used` TEXT
+);
@@ -40,7 +40,8 @@ pub(crate) async fn make_api(sol_state: SolGateState) -> Router {
         .route(
             "/config",
             get(config::api::get_config).put(config::api::put_config),
-        );
+        )
+        .route("/mirrors", get(files::mirrors::mirror_stats));
 
     //TODO: Actually add API endpoints
     Router::new()
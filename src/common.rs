@@ -23,7 +23,9 @@ pub enum Stability {
 pub enum Archive {
     // Ordering of these enum variants is used for preferential sorting.
     VP,       // Source is an entry in a VP file.
+    Zip,      // Source is an entry in a zip file.
     SevenZip, // Source is an entry in a 7z file.
+    Tar,      // Source is an entry in a tarball, compressed or not.
 }
 
 #[derive(
@@ -35,6 +37,8 @@ pub enum SourceFormat {
     Raw,
     SevenZip,
     VP,
+    Zip,
+    Tar, // Including .tar.gz and .tar.xz
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, sqlx::Type, sqlx::FromRow)]
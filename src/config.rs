@@ -83,12 +83,42 @@ impl Config {
     }
 }
 
-#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct LocalSettings {
     pub fs2_root: PathBuf,
     pub install_dir: PathBuf,
     pub temp_dir: PathBuf,
     pub hdd_mode: bool,
+    // How many files to download at once.
+    #[serde(default = "default_concurrent_downloads")]
+    pub concurrent_downloads: usize,
+    // Download limits in bytes per second, unlimited if not set or 0.
+    // The total is shared by every download, LAN gates and internet mirrors also have their own.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+    #[serde(default)]
+    pub lan_bandwidth_limit: Option<u64>,
+    #[serde(default)]
+    pub internet_bandwidth_limit: Option<u64>,
+}
+
+impl Default for LocalSettings {
+    fn default() -> Self {
+        Self {
+            fs2_root: Default::default(),
+            install_dir: Default::default(),
+            temp_dir: Default::default(),
+            hdd_mode: Default::default(),
+            concurrent_downloads: default_concurrent_downloads(),
+            bandwidth_limit: None,
+            lan_bandwidth_limit: None,
+            internet_bandwidth_limit: None,
+        }
+    }
+}
+
+fn default_concurrent_downloads() -> usize {
+    4
 }
 
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
@@ -31,6 +31,9 @@ pub async fn init(sqlitepath: PathBuf) -> Result<SqlitePool, MigrateError> {
 
 // All of the types for different tables in the database
 
+// Mods are identified by name, see `rel_names`.
+pub type ModID = String;
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Copy, sqlx::Type)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(rename_all = "lowercase")]
@@ -111,3 +114,15 @@ pub struct ModFlags {
     pub rel_id: i64,
     pub dep_id: i64,
 }
+
+// How downloads from a mirror have gone.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct MirrorStats {
+    pub host: String,
+    pub successes: i64,
+    pub failures: i64,
+    pub bytes: i64,
+    pub throughput: Option<f64>, // Bytes per second
+    pub last_error: Option<String>,
+    pub last_used: Option<String>,
+}
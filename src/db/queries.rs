@@ -9,7 +9,7 @@ use sqlx::{
     query_builder::QueryBuilder, sqlite::SqliteQueryResult, types::chrono::NaiveDate, Transaction,
 };
 
-use super::{DepType, LinkType, Rel, BIND_LIMIT};
+use super::{DepType, LinkType, MirrorStats, Rel, BIND_LIMIT};
 
 pub(crate) async fn add_release_names(
     names: &Vec<String>,
@@ -364,3 +364,67 @@ pub async fn get_package_files(
         .fetch_all(tx)
         .await
 }
+
+pub async fn set_mod_installed(
+    id: &str,
+    version: &str,
+    installed: bool,
+    tx: &mut Transaction<'_, sqlx::Sqlite>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE mods SET installed = ? \
+        WHERE rel_id = (SELECT rel_id FROM releases WHERE name = ? AND version = ?);",
+        installed,
+        id,
+        version
+    )
+    .execute(tx)
+    .await
+}
+
+/// Add how a download from `host` went to its stats.
+/// Throughput is a moving average, so a mirror that's got slow or fast recently shows it.
+pub async fn record_mirror_attempt(
+    host: &str,
+    success: bool,
+    bytes: i64,
+    throughput: Option<f64>,
+    error: Option<&str>,
+    tx: &mut Transaction<'_, sqlx::Sqlite>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    let successes = success as i64;
+    let failures = !success as i64;
+    sqlx::query!(
+        "INSERT INTO mirrors (host, successes, failures, bytes, throughput, last_error, last_used) \
+        VALUES (?, ?, ?, ?, ?, ?, datetime('now')) \
+        ON CONFLICT(host) DO UPDATE SET \
+        successes = successes + excluded.successes, \
+        failures = failures + excluded.failures, \
+        bytes = bytes + excluded.bytes, \
+        throughput = CASE \
+            WHEN excluded.throughput IS NULL THEN throughput \
+            WHEN throughput IS NULL THEN excluded.throughput \
+            ELSE throughput * 0.7 + excluded.throughput * 0.3 END, \
+        last_error = coalesce(excluded.last_error, last_error), \
+        last_used = excluded.last_used;",
+        host,
+        successes,
+        failures,
+        bytes,
+        throughput,
+        error
+    )
+    .execute(tx)
+    .await
+}
+
+pub async fn get_mirror_stats(
+    tx: &mut Transaction<'_, sqlx::Sqlite>,
+) -> Result<Vec<MirrorStats>, sqlx::Error> {
+    sqlx::query_as!(
+        MirrorStats,
+        "SELECT host, successes, failures, bytes, throughput, last_error, last_used FROM mirrors ORDER BY host;"
+    )
+    .fetch_all(tx)
+    .await
+}
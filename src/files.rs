@@ -1,38 +1,54 @@
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use crate::{db, SolGateState};
+use bytes::Bytes;
 use db::queries::*;
 use itertools::Itertools;
 use reqwest::Client;
+use sha2::{Digest, Sha256};
 use tokio::task::JoinError;
+use vp::reader::VPArchive;
 
-use crate::common::{Archive, Mod, SHA256Checksum, Source, SourceFormat};
+use crate::common::{Archive, Mod, Package, SHA256Checksum, Source, SourceFormat};
 use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
 use tokio::fs::{DirBuilder, File};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::StreamReader;
 
 type PathOneshot = (String, oneshot::Sender<Vec<u8>>);
 
 type VecPlusOneshot = (Vec<u8>, oneshot::Sender<Vec<u8>>);
 
 pub mod api;
+mod archives;
 pub mod compression;
 mod dag;
 mod hash;
+mod http;
 mod indexer;
+pub mod mirrors;
 pub mod readers;
-mod sevenz;
 mod solver;
+mod staging;
+pub mod store;
+pub mod throttle;
 mod util;
 
+use self::http::HttpRangeReader;
 use self::indexer::{index_dir, index_file, IndexError};
-use self::sevenz::sevenz_extract;
+use self::readers::{Get, GetRequest, ReaderError};
+pub use self::staging::recover_installs;
+use self::staging::InstallJob;
+use self::store::Store;
+use self::throttle::{Throttle, Transfer};
 use self::util::UrlError;
 
-type Manifest = Vec<ManifEntry>;
+pub type Manifest = Vec<ManifEntry>;
 #[derive(Clone)]
 pub struct ManifEntry {
     pub path: PathBuf,
@@ -64,25 +80,87 @@ pub struct VPEntry {
 pub enum DataPath {
     Raw(PathBuf),
     VPEntry(PathBuf, String),
-    SZEntry(PathBuf, String), // Not currently supported.
+    ZipEntry(PathBuf, String),
+    SZEntry(PathBuf, String),
+    TarEntry(PathBuf, String),
 }
 
 #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DagEdge {
     VP(String),
+    Zip(String),
     SZ(String),
+    Tar(String),
 }
 
+/// Work out where each file in a package goes, relative to the mod's folder.
+/// VP packages are built into `<folder>.vp`, with the folder stripped from their entries' paths.
+pub fn package_manifest(
+    package: &Package,
+    files: &[crate::common::File],
+    hashes: &HashMap<i64, SHA256Checksum>,
+) -> Manifest {
+    // hashes was queried using the h_ids of these files, so it can't be missing any.
+    let hash = |file: &crate::common::File| hashes.get(&file.h_id).unwrap().clone();
+    if package.is_vp {
+        let folder = package.folder.trim_end_matches('/');
+        let vp_name = match folder {
+            "." | "" => format!("{}.vp", package.name),
+            folder => format!("{}.vp", folder),
+        };
+        let entries = files
+            .iter()
+            .map(|f| {
+                let path = Path::new(&f.filepath);
+                VPEntry {
+                    path: path.strip_prefix(folder).unwrap_or(path).to_path_buf(),
+                    hash: hash(f),
+                }
+            })
+            .collect();
+        vec![ManifEntry {
+            path: PathBuf::from(vp_name),
+            ident: ManifIdent::VP(VPContents::Contents(entries)),
+        }]
+    } else {
+        files
+            .iter()
+            .map(|f| ManifEntry {
+                path: PathBuf::from(&f.filepath),
+                ident: ManifIdent::Raw(hash(f)),
+            })
+            .collect()
+    }
+}
+
+// How many files are stored, staged or checked at once while installing.
+// Unlike concurrent_downloads this is local disk work, not network, so it isn't a setting:
+// it only needs to be enough to overlap the reader pool with writing out the files.
+const INSTALL_CONCURRENCY: usize = 4;
+
 pub async fn install_files(
     manifest: Manifest,
     mod_info: Mod,
     state: &SolGateState,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), FileAcquisitionError> {
     // first we need to make sure we actually have a local copy of the files we need.
     acquire_files(state.clone(), &manifest).await?;
     // Construct full installation path.
-    let mut install_path =
-        PathBuf::from(state.config.read().await.local_settings.install_dir.clone());
+    let (install_dir, temp_dir) = {
+        let config = state.config.read().await;
+        (
+            config.local_settings.install_dir.clone(),
+            config.local_settings.temp_dir.clone(),
+        )
+    };
+    let mut install_path = install_dir.clone();
+    tokio::fs::create_dir_all(&install_dir).await?;
+    // Installs on another drive to the temp dir can only be given copies, so skip the store for them.
+    let store = Store::new(&temp_dir);
+    let store = match store.links_to(&install_dir).await? {
+        true => Some(store),
+        false => None,
+    };
     // optional mod_parent member, populated by mods but not TCs.
     if let Some(mod_parent) = &mod_info.parent {
         install_path.push(mod_parent);
@@ -91,6 +169,238 @@ pub async fn install_files(
     let modver = format!("{}-{}", &mod_info.name, &mod_info.version);
     install_path.push(modver);
 
+    // Everything's local now, so the reader pool can give us the contents of every entry.
+    // Fill the store first, once per file, so entries sharing a file don't both copy it in.
+    if let Some(store) = &store {
+        let stored = manifest
+            .iter()
+            .filter_map(|entry| match &entry.ident {
+                ManifIdent::Raw(hash) | ManifIdent::VP(VPContents::Hash(hash)) => {
+                    Some(hash.clone())
+                }
+                ManifIdent::VP(VPContents::Contents(_)) => None,
+            })
+            .unique()
+            .collect_vec();
+        let mut tasks = stream::iter(
+            stored
+                .into_iter()
+                .map(|hash| store_file(state.clone(), store.clone(), hash)),
+        )
+        .buffer_unordered(INSTALL_CONCURRENCY);
+        while let Some(result) = tasks.next().await {
+            result?
+        }
+    }
+
+    // Nothing touches the install until everything's staged and checked,
+    // so a failure part way leaves the previous version, or nothing, installed.
+    let job = InstallJob::begin(
+        &temp_dir,
+        &install_dir,
+        install_path,
+        &mod_info.name,
+        &mod_info.version,
+    )
+    .await?;
+    match stage_install(state, store.as_ref(), &job, manifest, &mod_info).await {
+        Ok(()) => {
+            // It's installed either way, the rest is tidying up.
+            if let Err(e) = job.commit().await {
+                eprintln!(
+                    "Couldn't clean up after installing {}: {}",
+                    mod_info.name, e
+                );
+            }
+            Ok(())
+        }
+        Err(e) => {
+            if let Err(rollback_err) = job.rollback().await {
+                eprintln!(
+                    "Couldn't roll back install of {}: {}",
+                    mod_info.name, rollback_err
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn stage_install(
+    state: &SolGateState,
+    store: Option<&Store>,
+    job: &InstallJob,
+    manifest: Manifest,
+    mod_info: &Mod,
+) -> Result<(), FileAcquisitionError> {
+    let stage = job.stage().to_path_buf();
+    {
+        let mut tasks = stream::iter(
+            manifest
+                .iter()
+                .cloned()
+                .map(|entry| install_entry(state.clone(), store.cloned(), stage.clone(), entry)),
+        )
+        .buffer_unordered(INSTALL_CONCURRENCY);
+        while let Some(result) = tasks.next().await {
+            result?
+        }
+    }
+    // Checked after staging, as a mismatch means something went wrong writing it.
+    let mut checks = stream::iter(manifest.into_iter().map(|entry| {
+        let path = stage.join(&entry.path);
+        tokio::task::spawn_blocking(move || verify_entry(&path, &entry.ident))
+    }))
+    .buffer_unordered(INSTALL_CONCURRENCY);
+    while let Some(result) = checks.next().await {
+        result??
+    }
+
+    job.swap().await?;
+    let mut tx = state.sql_pool.begin().await?;
+    set_mod_installed(&mod_info.name, &mod_info.version, true, &mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Check a staged file is exactly what the files table says it should be.
+fn verify_entry(path: &Path, ident: &ManifIdent) -> Result<(), FileAcquisitionError> {
+    let mismatch = || FileAcquisitionError::VerifyError(path.to_path_buf());
+    match ident {
+        ManifIdent::Raw(hash) | ManifIdent::VP(VPContents::Hash(hash)) => {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+            if hasher.finalize().as_slice() != hash.0.as_slice() {
+                return Err(mismatch());
+            }
+        }
+        ManifIdent::VP(VPContents::Contents(entries)) => {
+            let archive = VPArchive::open(path).map_err(std::io::Error::from)?;
+            if archive.index().flatten().len() != entries.len() {
+                return Err(mismatch());
+            }
+            for entry in entries {
+                let file = archive
+                    .entry(entry.path.to_string_lossy().as_ref())
+                    .ok_or_else(mismatch)?;
+                let mut hasher = Sha256::new();
+                for chunk in archive.chunks(file)? {
+                    hasher.update(chunk?);
+                }
+                if hasher.finalize().as_slice() != entry.hash.0.as_slice() {
+                    return Err(mismatch());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn install_entry(
+    state: SolGateState,
+    store: Option<Store>,
+    install_path: PathBuf,
+    entry: ManifEntry,
+) -> Result<(), FileAcquisitionError> {
+    let dest = install_dest(&install_path, &entry.path)?;
+    DirBuilder::new()
+        .recursive(true)
+        .create(dest.parent().unwrap())
+        .await?;
+    match &entry.ident {
+        // A VP we know the hash of is a file like any other.
+        ManifIdent::Raw(hash) | ManifIdent::VP(VPContents::Hash(hash)) => match store {
+            Some(store) => {
+                store.place(hash, &dest).await?;
+                Ok(())
+            }
+            None => write_file(&state, hash, &dest).await,
+        },
+        ManifIdent::VP(VPContents::Contents(entries)) => {
+            let mut outfile = File::create(&dest).await?;
+            vp_writer(&state, entries, &mut outfile).await
+        }
+    }
+}
+
+async fn write_file(
+    state: &SolGateState,
+    hash: &SHA256Checksum,
+    dest: &Path,
+) -> Result<(), FileAcquisitionError> {
+    let mut contents = request_contents(state, hash).await;
+    let mut outfile = File::create(dest).await?;
+    while let Some(chunk) = contents.recv().await {
+        outfile.write_all(&chunk?).await?;
+    }
+    outfile.flush().await?;
+    Ok(())
+}
+
+// Paths in a manifest come from whoever published the mod, so don't let them escape its folder.
+fn install_dest(install_path: &Path, path: &Path) -> Result<PathBuf, FileAcquisitionError> {
+    let safe = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !safe || path.file_name().is_none() {
+        return Err(FileAcquisitionError::LogicError(format!(
+            "Refusing to install to {}",
+            path.display()
+        )));
+    }
+    Ok(install_path.join(path))
+}
+
+// Ask the reader pool for the contents of a file we have a local copy of.
+async fn request_contents(
+    state: &SolGateState,
+    hash: &SHA256Checksum,
+) -> mpsc::Receiver<Result<Bytes, ReaderError>> {
+    let (tx, rx) = mpsc::channel(5);
+    state
+        .reader_pool
+        .tx
+        .send(GetRequest {
+            contents: Get::CS(hash.clone()),
+            channel: tx,
+            raw: false,
+        })
+        .await
+        .expect("Send failed, but it's infallible???");
+    rx
+}
+
+// Copy a file into the store, and record it as a local source so it's found before downloading.
+async fn store_file(
+    state: SolGateState,
+    store: Store,
+    hash: SHA256Checksum,
+) -> Result<(), FileAcquisitionError> {
+    if store.contains(&hash).await {
+        return Ok(());
+    }
+    let contents = request_contents(&state, &hash).await;
+    let (path, size) = store.add(&hash, contents).await?;
+    let path = path.to_string_lossy().to_string();
+    let mut tx = state.sql_pool.begin().await?;
+    // Someone else may have stored it at the same time.
+    let existing = get_sources_from_hash(&hash, &mut tx).await?;
+    let h_id = get_id_from_hash(&hash, &mut tx).await?;
+    tx.commit().await?;
+    if existing.iter().any(|s| s.path == path) {
+        return Ok(());
+    }
+    // A new transaction, as one that's read can't write once someone else has.
+    let source = Source {
+        location: db::SourceLocation::Local,
+        path,
+        h_id,
+        size: size.try_into().unwrap(),
+        format: SourceFormat::Raw,
+    };
+    let mut tx = state.sql_pool.begin().await?;
+    add_sources(&vec![source], &mut tx).await?;
+    tx.commit().await?;
     Ok(())
 }
 
@@ -116,12 +426,23 @@ pub async fn acquire_files(
         .collect::<Vec<SHA256Checksum>>();
 
     let mut tx = state.sql_pool.begin().await?;
-    let hids = get_hash_ids(&hashes, &mut tx)
-        .await?
+    let hash_ids = get_hash_ids(&hashes, &mut tx).await?;
+    tx.commit().await?;
+    // A hash we've never seen can't have a source, so there's no way to get it.
+    let known = hash_ids
+        .iter()
+        .map(|(hash, _)| hash)
+        .collect::<HashSet<_>>();
+    if let Some(unknown) = hashes.iter().find(|h| !known.contains(h)) {
+        return Err(FileAcquisitionError::LogicError(format!(
+            "No source for {}",
+            hex::encode(&unknown.0)
+        )));
+    }
+    let hids = hash_ids
         .into_iter()
         .map(|(_, h_id)| h_id)
         .collect::<Vec<_>>();
-    tx.commit().await?;
 
     // Files can be inside other ones, i.e. inside VPs and inside 7z archives.
     // We construct a DAG of what archives contain what files.
@@ -133,31 +454,37 @@ pub async fn acquire_files(
     let missing = calculate_missing(&state, &hids, &hid_hierarchy).await?;
 
     // We find a set of sources that minimize the total required download.
-    let sources = calculate_fetches(&state, &missing, &hid_hierarchy).await?;
+    let fetches = calculate_fetches(&state, &missing, &hid_hierarchy).await?;
 
     // We'll set up a stream of http fetch tasks
-    let mut tasks = stream::iter(sources.into_iter().map(|source| {
+    let concurrent_downloads = state
+        .config
+        .read()
+        .await
+        .local_settings
+        .concurrent_downloads;
+    let mut tasks = stream::iter(fetches.into_iter().map(|fetch| {
         let s = state.clone();
-        async move { fetch_files(&source, &s).await.unwrap() }
+        async move { fetch_files(&fetch, &s).await }
     }))
-    .buffer_unordered(4) // TODO Replace 4 with parallel download count from config.
+    .buffer_unordered(concurrent_downloads.max(1))
     .then(|loc| async {
-        match loc {
+        match loc? {
             FetchResult::Directory(dir) => {
-                index_dir(&dir, state.clone(), db::SourceLocation::Temp).await
+                index_dir(&dir, state.clone(), db::SourceLocation::Temp).await?
             }
             FetchResult::File(file) => {
-                index_file(&file, state.clone(), db::SourceLocation::Temp).await
+                index_file(&file, state.clone(), db::SourceLocation::Temp).await?
             }
         }
+        Ok::<(), FileAcquisitionError>(())
     })
     .boxed();
     // need to handle these tasks.
     while let Some(result) = tasks.next().await {
         result?
     }
-
-    todo!()
+    Ok(())
 }
 
 pub async fn generate_dag(
@@ -165,6 +492,10 @@ pub async fn generate_dag(
     ids: &Vec<i64>,
 ) -> Result<dag::HashDAG<i64, DagEdge>, FileAcquisitionError> {
     let mut hid_hierarchy = dag::HashDAG::<i64, DagEdge>::new();
+    // Files that aren't in any archive still need a node, so we can look for their sources.
+    for id in ids {
+        hid_hierarchy.add(id);
+    }
     let mut sql_tx = state.sql_pool.begin().await?;
     // We're going to loop over the parents, parent's parents etc. until there's none left.
     // This will fill the DAG with every possible container of our needed files.
@@ -179,6 +510,8 @@ pub async fn generate_dag(
             let edge = match archive_entry.archive_type {
                 Archive::SevenZip => DagEdge::SZ(archive_entry.file_path.clone()),
                 Archive::VP => DagEdge::VP(archive_entry.file_path.clone()),
+                Archive::Zip => DagEdge::Zip(archive_entry.file_path.clone()),
+                Archive::Tar => DagEdge::Tar(archive_entry.file_path.clone()),
             };
             hid_hierarchy
                 .add_relationship(&archive_entry.file_id, &archive_entry.archive_id, edge)
@@ -217,6 +550,7 @@ pub async fn calculate_missing(
     for source in sources {
         if source.location.is_local() {
             let source_hid = source.h_id;
+            missing_hids.remove(&source_hid);
             let descendants = hid_hierarchy.descendants(&source_hid).unwrap();
             for descendant in descendants {
                 missing_hids.remove(&descendant);
@@ -227,45 +561,160 @@ pub async fn calculate_missing(
     Ok(missing_hids.into_iter().collect())
 }
 
+/// A source to download, and if it's a remote VP, the entries we need from it.
+/// Fetching just those saves downloading a whole VP when only a few files in it changed.
+pub struct Fetch {
+    pub source: Source,
+    // Each entry with the hash its contents should have.
+    pub entries: Option<Vec<(vp::types::VPFile, SHA256Checksum)>>,
+}
+
 pub async fn calculate_fetches(
     state: &SolGateState,
     missing: &Vec<i64>,
     hid_hierarchy: &dag::HashDAG<i64, DagEdge>,
-) -> Result<Vec<Source>, FileAcquisitionError> {
+) -> Result<Vec<Fetch>, FileAcquisitionError> {
     // We now need to solve our fetch problem.
     // How do we get the missing files using the smallest amount of downloading?
     // We construct a map of Source to Vec<hash_id>,
     // so we know which of our missing files each source contains.
+    // A missing file might be available directly, as well as from the archives it's in.
     let missing_source_hids: Vec<i64> = missing
         .iter()
-        .flat_map(|m| hid_hierarchy.ancestors(m).unwrap())
+        .flat_map(|m| {
+            let mut ancestors = hid_hierarchy.ancestors(m).unwrap();
+            ancestors.push(*m);
+            ancestors
+        })
         .unique()
         .collect();
     let mut tx = state.sql_pool.begin().await?;
     let missing_sources = get_sources_from_ids(&missing_source_hids, &mut tx).await?;
+    // Planning VP entries makes range requests, so don't hold the database up while they happen.
+    tx.commit().await?;
     let remote_sources = missing_sources
         .into_iter()
         .filter(|s| !s.location.is_local())
         .collect::<Vec<Source>>();
 
-    let sourcemap = HashMap::<Source, Vec<i64>>::from_iter(remote_sources.into_iter().map(|s| {
-        (
-            s.clone(),
-            hid_hierarchy
-                .descendants(&(s.h_id))
-                .unwrap()
+    let mut sourcemap = HashMap::<Source, Vec<i64>>::new();
+    let mut vp_entries = HashMap::<String, Vec<(i64, vp::types::VPFile)>>::new();
+    for mut source in remote_sources {
+        let mut hids: Vec<i64> = hid_hierarchy
+            .descendants(&(source.h_id))
+            .unwrap()
+            .into_iter()
+            .filter(|d| missing.contains(d))
+            .collect();
+        if missing.contains(&source.h_id) {
+            hids.push(source.h_id);
+        }
+        // Remote VPs can be read a range at a time,
+        // so only the entries we'd fetch count towards their size.
+        if source.format == SourceFormat::VP {
+            let client = state.http_client.clone();
+            if let Some(entries) = plan_vp_entries(client, &source, &hids, hid_hierarchy).await {
+                source.size = entries.iter().map(|(_, e)| e.size as i64).sum();
+                vp_entries.insert(source.path.clone(), entries);
+            }
+        }
+        sourcemap.insert(source, hids);
+    }
+    let mut tx = state.sql_pool.begin().await?;
+    // The solver can't cover a file nothing provides.
+    let covered = sourcemap.values().flatten().collect::<HashSet<_>>();
+    if let Some(uncovered) = missing.iter().find(|m| !covered.contains(m)) {
+        let hash = get_hashes_from_ids(&vec![*uncovered], &mut tx).await?;
+        return Err(FileAcquisitionError::LogicError(format!(
+            "No source for {}",
+            hash.first()
+                .map_or(uncovered.to_string(), |h| hex::encode(&h.val.0))
+        )));
+    }
+    // VP entries are checked against these as they're fetched.
+    let entry_hids = vp_entries
+        .values()
+        .flatten()
+        .map(|(hid, _)| *hid)
+        .unique()
+        .collect::<Vec<_>>();
+    let entry_hashes = get_hashes_from_ids(&entry_hids, &mut tx)
+        .await?
+        .into_iter()
+        .map(|hash| (hash.id, hash.val))
+        .collect::<HashMap<_, _>>();
+    tx.commit().await?;
+    let mut vp_entries = vp_entries
+        .into_iter()
+        .map(|(path, entries)| {
+            let entries = entries
                 .into_iter()
-                .filter(|d| missing.contains(&d))
-                .collect(),
-        )
-    }));
+                .map(|(hid, file)| match entry_hashes.get(&hid) {
+                    Some(hash) => Ok((file, hash.clone())),
+                    None => Err(FileAcquisitionError::LogicError(format!(
+                        "No hash for file {}",
+                        hid
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((path, entries))
+        })
+        .collect::<Result<HashMap<_, _>, FileAcquisitionError>>()?;
 
     // At this point we offload calculation of this weighted set coverage problem to the fetch solver.
     // This is pretty CPU intensive and might block for a while, so run seperately.
     let minimized_sources =
         tokio::task::spawn_blocking(move || solver::solve_files(sourcemap)).await?;
 
-    Ok(minimized_sources)
+    Ok(minimized_sources
+        .into_iter()
+        .map(|source| Fetch {
+            entries: vp_entries.remove(&source.path),
+            source,
+        })
+        .collect())
+}
+
+// Work out which entries of a remote VP hold the files we want by reading its index,
+// if every one of those files is directly inside it.
+// Returned entries are named with their full path inside the VP, alongside the hash ID of their file.
+async fn plan_vp_entries(
+    client: Client,
+    source: &Source,
+    hids: &[i64],
+    hid_hierarchy: &dag::HashDAG<i64, DagEdge>,
+) -> Option<Vec<(i64, vp::types::VPFile)>> {
+    let paths = hids
+        .iter()
+        .map(|hid| match hid_hierarchy.get_edge_data(hid, &source.h_id) {
+            Some(DagEdge::VP(path)) => Some((*hid, path.clone())),
+            _ => None,
+        })
+        .collect::<Option<Vec<(i64, String)>>>()?;
+    let mut reader = HttpRangeReader::new(client, &source.path);
+    let index = match vp::range::range_index(&mut reader).await {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!(
+                "Can't read the index of {}, fetching all of it: {}",
+                source.path, e
+            );
+            return None;
+        }
+    };
+    paths
+        .into_iter()
+        .map(|(hid, path)| {
+            let file = index.locate(path.as_str()).ok()?;
+            Some((
+                hid,
+                vp::types::VPFile {
+                    name: vp::path::VPPath::from(path).to_string(),
+                    ..file
+                },
+            ))
+        })
+        .collect()
 }
 
 enum FetchResult {
@@ -274,9 +723,10 @@ enum FetchResult {
 }
 
 async fn fetch_files(
-    source: &Source,
+    fetch: &Fetch,
     state: &SolGateState,
 ) -> Result<FetchResult, FileAcquisitionError> {
+    let source = &fetch.source;
     // Step one, download file.
     let mut tx = state.sql_pool.begin().await?;
     let hash_vec = get_hashes_from_ids(&vec![source.h_id], &mut tx).await?;
@@ -291,58 +741,355 @@ async fn fetch_files(
         .temp_dir
         .join(hash_str);
     let client = state.http_client.clone();
-    get_http_source(client, &source.path, &save_loc).await?;
+    let mut entry_dir = save_loc.clone().into_os_string();
+    entry_dir.push("-entries");
+    let entry_dir = PathBuf::from(entry_dir);
+    let size: u64 = source.size.try_into().map_err(|_| {
+        FileAcquisitionError::LogicError(format!(
+            "{} has an invalid size of {}",
+            source.path, source.size
+        ))
+    })?;
+    // Work down the mirrors until one of them gives us the file.
+    // Partial downloads are kept between them, so the next mirror carries on where the last left off.
+    let mirrors = mirrors::rank_mirrors(state, source).await?;
+    for (i, mirror) in mirrors.iter().enumerate() {
+        // Another mirror can take over from a slow one, but the last one has to do.
+        let last = i + 1 == mirrors.len();
+        let started = Instant::now();
+        let (result, bytes) = match &fetch.entries {
+            // Just the entries we need, which get indexed as loose files.
+            Some(entries) => {
+                let result = get_http_vp_entries(
+                    client.clone(),
+                    &state.throttle,
+                    &mirror.path,
+                    entries,
+                    &entry_dir,
+                )
+                .await;
+                let bytes = match result {
+                    Ok(()) => entries.iter().map(|(e, _)| e.size).sum(),
+                    Err(_) => 0,
+                };
+                (result, bytes)
+            }
+            None => {
+                let partial = partial_path(&save_loc);
+                let before = tokio::fs::metadata(&partial).await.map_or(0, |m| m.len());
+                let result = get_http_source(
+                    client.clone(),
+                    &state.throttle,
+                    &mirror.path,
+                    &save_loc,
+                    &hash.val,
+                    size,
+                    !last,
+                )
+                .await;
+                let after = match result {
+                    Ok(()) => size,
+                    Err(_) => tokio::fs::metadata(&partial).await.map_or(0, |m| m.len()),
+                };
+                (result, after.saturating_sub(before))
+            }
+        };
+        mirrors::record_attempt(state, &mirror.path, &result, bytes, started.elapsed()).await;
+        match result {
+            Ok(()) => break,
+            Err(e) if !last && mirrors::mirror_failed(&e) => {
+                eprintln!(
+                    "Downloading {} failed, trying another mirror: {}",
+                    mirror.path, e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if fetch.entries.is_some() {
+        return Ok(FetchResult::Directory(entry_dir));
+    }
     let location_string = save_loc.clone().to_string_lossy().to_string();
     // Step two, index it.
-    if source.format == SourceFormat::SevenZip {
+    if matches!(
+        source.format,
+        SourceFormat::SevenZip | SourceFormat::Zip | SourceFormat::Tar
+    ) {
+        // Keep the archive as a source too, so its entries can still be read out of it
+        // once the extracted copies have been cleaned up.
+        // We already know its hash and what's in it, so there's no need to index it.
+        let archive_source = Source {
+            location: db::SourceLocation::Temp,
+            path: location_string,
+            h_id: source.h_id,
+            size: tokio::fs::metadata(&save_loc)
+                .await?
+                .len()
+                .try_into()
+                .unwrap(),
+            format: source.format,
+        };
+        let mut tx = state.sql_pool.begin().await?;
+        add_sources(&vec![archive_source], &mut tx).await?;
+        tx.commit().await?;
         // Extract and index our contents.
-        let extract_dir = save_loc.join("/extract");
-        sevenz_extract(&save_loc, &extract_dir)
-            .await
-            .expect("7z extraction failed");
+        let mut extract_dir = save_loc.clone().into_os_string();
+        extract_dir.push("-extract");
+        let extract_dir = PathBuf::from(extract_dir);
+        archives::extract(source.format, &save_loc, &extract_dir).await?;
         Ok(FetchResult::Directory(extract_dir.clone()))
     } else {
         Ok(FetchResult::File(save_loc.clone()))
     }
 }
 
+// How many times in a row a download can fail to get any further before we give up on it.
+const DOWNLOAD_ATTEMPTS: usize = 5;
+// How long a download can go without receiving anything before we count it as dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+// Downloads slower than this after the grace period get moved to another mirror, if there is one.
+const MIN_THROUGHPUT: u64 = 32 * 1024;
+const SLOW_GRACE: Duration = Duration::from_secs(20);
+
+/// Where a download goes until it's complete and verified.
+pub fn partial_path(save_loc: &Path) -> PathBuf {
+    let mut partial = save_loc.to_path_buf().into_os_string();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+/// Download `url` to `save_loc`, checking it's `size` bytes long and matches `hash`.
+/// It's written to a `.part` file alongside until then, so a dropped connection
+/// is resumed with a range request rather than leaving a truncated file that looks complete.
+/// If there's another mirror to fall back on, slow downloads and error responses
+/// give up on this one straight away, so the next mirror can take over.
 pub async fn get_http_source(
     client: Client,
+    throttle: &Throttle,
     url: &str,
     save_loc: &impl AsRef<Path>,
+    hash: &SHA256Checksum,
+    size: u64,
+    has_fallback: bool,
 ) -> Result<(), FileAcquisitionError> {
-    let res = client.get(url).send().await?;
-    let mut stream = res.bytes_stream();
-
-    let dir = save_loc.as_ref().clone().parent().unwrap();
+    let save_loc = save_loc.as_ref();
+    let dir = save_loc.parent().unwrap();
     tokio::fs::DirBuilder::new()
         .recursive(true)
         .create(dir)
         .await?;
-    let mut outfile = File::create(save_loc).await?;
+    let partial = partial_path(save_loc);
+    let transfer = throttle.transfer(url);
+
+    let mut failures = 0;
+    loop {
+        let offset = match tokio::fs::metadata(&partial).await {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if offset >= size {
+            break;
+        }
+        match download_from(&client, &transfer, url, &partial, offset, has_fallback).await {
+            Ok(()) => (),
+            Err(FileAcquisitionError::SlowDownload(_))
+                if !has_fallback && failures + 1 < DOWNLOAD_ATTEMPTS =>
+            {
+                eprintln!("Download of {} stalled, resuming", url);
+            }
+            // Dropped connections are worth resuming, but a server that answers
+            // with an error is only worth asking again if there's nowhere else to go.
+            // No point asking again at all if the server says it's our fault, i.e. a 404.
+            Err(FileAcquisitionError::NetworkError(e))
+                if failures + 1 < DOWNLOAD_ATTEMPTS
+                    && e.status()
+                        .is_none_or(|s| !has_fallback && s.is_server_error()) =>
+            {
+                eprintln!("Download of {} interrupted, resuming: {}", url, e);
+            }
+            Err(e) => return Err(e),
+        }
+        let got = tokio::fs::metadata(&partial).await.map_or(0, |m| m.len());
+        // Only count attempts that didn't get us anywhere, so a flaky server can still finish.
+        if got > offset {
+            failures = 0;
+        } else {
+            failures += 1;
+            if failures >= DOWNLOAD_ATTEMPTS {
+                return Err(FileAcquisitionError::DownloadVerifyError(
+                    url.to_string(),
+                    format!("stopped at {} of {} bytes", got, size),
+                ));
+            }
+            // Give the server a moment before asking again.
+            tokio::time::sleep(Duration::from_secs(failures as u64)).await;
+        }
+    }
 
-    while let Some(item) = stream.next().await {
-        let chunk = item?;
-        outfile.write_all(&chunk).await?;
+    let verify_path = partial.clone();
+    let (got_size, got_hash) = tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        let got_size = std::io::copy(&mut std::fs::File::open(verify_path)?, &mut hasher)?;
+        Ok::<_, std::io::Error>((got_size, hasher.finalize()))
+    })
+    .await??;
+    let mismatch = if got_size != size {
+        Some(format!("expected {} bytes, got {}", size, got_size))
+    } else if got_hash.as_slice() != hash.0.as_slice() {
+        Some(format!(
+            "expected hash {}, got {}",
+            hex::encode(&hash.0),
+            hex::encode(got_hash)
+        ))
+    } else {
+        None
+    };
+    if let Some(reason) = mismatch {
+        // There's no telling which part is bad, so start again from scratch next time.
+        tokio::fs::remove_file(&partial).await?;
+        return Err(FileAcquisitionError::DownloadVerifyError(
+            url.to_string(),
+            reason,
+        ));
     }
+    tokio::fs::rename(&partial, save_loc).await?;
     Ok(())
 }
 
-pub async fn vp_writer<T: AsyncWrite + std::marker::Unpin>(
+// Append whatever we can get of `url` from `offset` onwards to `partial`.
+async fn download_from(
+    client: &Client,
+    transfer: &Transfer,
+    url: &str,
+    partial: &Path,
+    offset: u64,
+    has_fallback: bool,
+) -> Result<(), FileAcquisitionError> {
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let res = request.send().await?;
+    if res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // What we've got is longer than the file, so it can't be right.
+        tokio::fs::remove_file(partial).await?;
+        return Ok(());
+    }
+    let res = res.error_for_status()?;
+    // Servers that don't do ranges send the whole file again.
+    let resumed = res.status() == reqwest::StatusCode::PARTIAL_CONTENT
+        && res
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .is_some_and(|range| range.starts_with(&format!("bytes {}-", offset)));
+    let mut outfile = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(partial)
+        .await?;
+    let mut stream = res.bytes_stream();
+    let started = Instant::now();
+    let mut received = 0;
+    // Time spent held back by the bandwidth limits isn't the mirror's fault.
+    let mut throttled = Duration::ZERO;
+    let streamed = async {
+        loop {
+            let item = tokio::time::timeout(STALL_TIMEOUT, stream.next())
+                .await
+                .map_err(|_| FileAcquisitionError::SlowDownload(url.to_string()))?;
+            let chunk = match item {
+                Some(chunk) => chunk?,
+                None => return Ok(()),
+            };
+            outfile.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+            let waited = Instant::now();
+            transfer.take(chunk.len() as u64).await;
+            throttled += waited.elapsed();
+            let elapsed = started.elapsed().saturating_sub(throttled);
+            if has_fallback && elapsed > SLOW_GRACE && received < MIN_THROUGHPUT * elapsed.as_secs()
+            {
+                return Err(FileAcquisitionError::SlowDownload(url.to_string()));
+            }
+        }
+    }
+    .await;
+    // Keep what did arrive, so the next attempt carries on from there.
+    outfile.flush().await?;
+    streamed
+}
+
+/// Fetch entries from a remote VP with range requests, and save them decompressed under `dir`.
+/// Each entry is checked against its hash first, so a bad one is never indexed as a loose file.
+pub async fn get_http_vp_entries(
+    client: Client,
+    throttle: &Throttle,
+    url: &str,
+    entries: &[(vp::types::VPFile, SHA256Checksum)],
+    dir: &impl AsRef<Path>,
+) -> Result<(), FileAcquisitionError> {
+    let mut reader = HttpRangeReader::new(client, url);
+    let transfer = throttle.transfer(url);
+    for (entry, hash) in entries {
+        // Names come from someone else's VP, so don't let them escape `dir`.
+        let save_loc = vp::path::VPPath::from(&entry.name)
+            .to_fs_path(dir)
+            .ok_or_else(|| {
+                FileAcquisitionError::LogicError(format!(
+                    "Refusing to save VP entry {} from {}",
+                    entry.name, url
+                ))
+            })?;
+        transfer.take(entry.size).await;
+        let data = vp::range::range_read_entry(&mut reader, entry).await?;
+        let got_hash = Sha256::digest(&data);
+        if got_hash.as_slice() != hash.0.as_slice() {
+            return Err(FileAcquisitionError::DownloadVerifyError(
+                url.to_string(),
+                format!(
+                    "{} expected hash {}, got {}",
+                    entry.name,
+                    hex::encode(&hash.0),
+                    hex::encode(got_hash)
+                ),
+            ));
+        }
+
+        DirBuilder::new()
+            .recursive(true)
+            .create(save_loc.parent().unwrap())
+            .await?;
+        tokio::fs::write(&save_loc, data).await?;
+    }
+    Ok(())
+}
+
+/// Build a VP from files we have local copies of, streaming each entry from the reader pool.
+pub async fn vp_writer<T: AsyncWrite + AsyncSeek + std::marker::Unpin>(
+    state: &SolGateState,
+    entries: &[VPEntry],
     file_like: &mut T,
-    mut rx: mpsc::Receiver<(VPEntry, Option<oneshot::Receiver<Vec<u8>>>)>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    todo!(); // Nowhere near complete lol.
-             // let vpstuff = 6; // TODO
-             // while let Some((info, data)) = rx.recv().await {
-             //     let d_len = data.len();
-             //     file_like.write_all(&data).await?;
-             //     // Uuh
-             // }
+) -> Result<(), FileAcquisitionError> {
+    let mut writer = vp::writer::AsyncVPWriter::new(file_like).await?;
+    // One entry at a time, as each read holds a blocking thread until we've taken its chunks.
+    for entry in entries {
+        let contents = request_contents(state, &entry.hash).await;
+        let mut reader = StreamReader::new(
+            ReceiverStream::new(contents).map(|chunk| chunk.map_err(std::io::Error::other)),
+        );
+        let path = entry.path.to_string_lossy();
+        writer.add_reader(&path, &mut reader, 0).await?;
+    }
+    writer.finish().await?;
+    Ok(())
 }
 #[derive(Debug, thiserror::Error)]
 pub enum FileAcquisitionError {
-    #[error("Network Error")]
+    #[error("Network Error: {0}")]
     NetworkError(reqwest::Error),
     #[error("IO Error")]
     IOError(std::io::Error),
@@ -352,6 +1099,12 @@ pub enum FileAcquisitionError {
     JoinError(JoinError),
     #[error("Program Logic Error: {0}")]
     LogicError(String),
+    #[error("{0:?} doesn't match its hash")]
+    VerifyError(PathBuf),
+    #[error("Download from {0} failed: {1}")]
+    DownloadVerifyError(String, String),
+    #[error("Download from {0} was too slow")]
+    SlowDownload(String),
 }
 
 impl From<sqlx::Error> for FileAcquisitionError {
@@ -385,6 +1138,254 @@ impl From<IndexError> for FileAcquisitionError {
         match value {
             IndexError::IOError(ioerr) => FileAcquisitionError::IOError(ioerr),
             IndexError::SqlxError(sqlerr) => FileAcquisitionError::SqlxError(sqlerr),
+            IndexError::VPParseError(vperr) => FileAcquisitionError::IOError(vperr.into()),
+            invalid @ IndexError::InvalidVP(..) => FileAcquisitionError::IOError(
+                std::io::Error::new(std::io::ErrorKind::InvalidData, invalid.to_string()),
+            ),
+            IndexError::ReaderError(readerr) => readerr.into(),
         }
     }
 }
+
+impl From<ReaderError> for FileAcquisitionError {
+    fn from(value: ReaderError) -> Self {
+        match value {
+            ReaderError::IOError(ioerr) => FileAcquisitionError::IOError(ioerr),
+            ReaderError::SqlxError(sqlerr) => FileAcquisitionError::SqlxError(sqlerr),
+            ReaderError::JoinError(joinerr) => FileAcquisitionError::JoinError(joinerr),
+            other => FileAcquisitionError::LogicError(other.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::File;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::RwLock;
+
+    fn package(folder: &str, is_vp: bool) -> Package {
+        Package {
+            p_id: 1,
+            rel_id: 1,
+            name: "Core".to_string(),
+            notes: String::new(),
+            status: db::DepType::Required,
+            environment: None,
+            folder: folder.to_string(),
+            is_vp,
+        }
+    }
+
+    fn files() -> (Vec<File>, HashMap<i64, SHA256Checksum>) {
+        let files = ["data/tables/ships.tbl", "data/hud/radar.dds"]
+            .iter()
+            .enumerate()
+            .map(|(i, path)| File {
+                p_id: 1,
+                h_id: i as i64,
+                filepath: path.to_string(),
+            })
+            .collect();
+        let hashes = (0..2)
+            .map(|i| (i, SHA256Checksum(vec![i as u8; 32])))
+            .collect();
+        (files, hashes)
+    }
+
+    #[test]
+    fn raw_package_manifest() {
+        let (files, hashes) = files();
+        let manifest = package_manifest(&package("", false), &files, &hashes);
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest[0].path, PathBuf::from("data/tables/ships.tbl"));
+        assert!(matches!(&manifest[1].ident, ManifIdent::Raw(hash) if hash.0 == vec![1; 32]));
+    }
+
+    #[test]
+    fn vp_package_manifest() {
+        let (files, hashes) = files();
+        // Files are listed relative to the mod, so the folder is stripped off inside the VP.
+        let files = files
+            .into_iter()
+            .map(|f| File {
+                filepath: format!("core/{}", f.filepath),
+                ..f
+            })
+            .collect::<Vec<_>>();
+        let manifest = package_manifest(&package("core/", true), &files, &hashes);
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[0].path, PathBuf::from("core.vp"));
+        match &manifest[0].ident {
+            ManifIdent::VP(VPContents::Contents(entries)) => {
+                let paths = entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+                assert_eq!(
+                    paths,
+                    [
+                        PathBuf::from("data/tables/ships.tbl"),
+                        PathBuf::from("data/hud/radar.dds")
+                    ]
+                );
+            }
+            _ => panic!("VP package should have a VP in its manifest"),
+        }
+        // No folder, so it's named after the package.
+        let manifest = package_manifest(&package(".", true), &[], &hashes);
+        assert_eq!(manifest[0].path, PathBuf::from("Core.vp"));
+    }
+
+    // Serve `body` at the URL returned, cutting the first response off half way through.
+    // Also returns the Range header of each request, if it had one.
+    async fn flaky_server(body: Vec<u8>) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let seen = ranges.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(len) => request.extend(&buf[..len]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let range = request.lines().find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("range")
+                        .then(|| value.trim().to_string())
+                });
+                let first = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(range.clone());
+                    seen.len() == 1
+                };
+                let start = range
+                    .as_deref()
+                    .and_then(|r| r.strip_prefix("bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok())
+                    .unwrap_or(0);
+                let rest = &body[start..];
+                let status = match start {
+                    0 => "200 OK".to_string(),
+                    _ => format!(
+                        "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    ),
+                };
+                let header = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    rest.len()
+                );
+                let sent = if first { &rest[..rest.len() / 2] } else { rest };
+                let _ = socket.write_all(header.as_bytes()).await;
+                let _ = socket.write_all(sent).await;
+            }
+        });
+        (url, ranges)
+    }
+
+    // The download goes in a fresh directory, removed when the returned one is dropped.
+    fn test_download() -> (
+        Vec<u8>,
+        SHA256Checksum,
+        tempfile::TempDir,
+        PathBuf,
+        Throttle,
+    ) {
+        let body = (0..200_000u32)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let hash = SHA256Checksum(Sha256::digest(&body).to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let save_loc = dir.path().join("download");
+        let config = Arc::new(RwLock::new(crate::config::Config::default()));
+        (body, hash, dir, save_loc, Throttle::new(config))
+    }
+
+    #[tokio::test]
+    async fn resume_dropped_download() {
+        let (body, hash, _dir, save_loc, throttle) = test_download();
+        let (url, ranges) = flaky_server(body.clone()).await;
+        let size = body.len() as u64;
+        get_http_source(
+            Client::new(),
+            &throttle,
+            &url,
+            &save_loc,
+            &hash,
+            size,
+            false,
+        )
+        .await
+        .unwrap();
+        // The second request carried on from where the first was cut off.
+        assert_eq!(
+            *ranges.lock().unwrap(),
+            [None, Some(format!("bytes={}-", body.len() / 2))]
+        );
+        assert_eq!(std::fs::read(&save_loc).unwrap(), body);
+        assert!(!partial_path(&save_loc).exists());
+    }
+
+    #[tokio::test]
+    async fn reject_bad_downloads() {
+        let (body, _, _dir, save_loc, throttle) = test_download();
+        let size = body.len() as u64;
+        let (url, _) = flaky_server(body.clone()).await;
+        let wrong_hash = SHA256Checksum(vec![0; 32]);
+        let result = get_http_source(
+            Client::new(),
+            &throttle,
+            &url,
+            &save_loc,
+            &wrong_hash,
+            size,
+            false,
+        )
+        .await;
+        assert!(
+            matches!(result, Err(FileAcquisitionError::DownloadVerifyError(_, reason)) if reason.contains("hash"))
+        );
+        // Nothing's kept to resume from, as there's no telling which part was bad.
+        assert!(!partial_path(&save_loc).exists());
+        assert!(!save_loc.exists());
+
+        // Right contents, but more of them than expected.
+        let hash = SHA256Checksum(Sha256::digest(&body).to_vec());
+        let (url, _) = flaky_server(body.clone()).await;
+        let result = get_http_source(
+            Client::new(),
+            &throttle,
+            &url,
+            &save_loc,
+            &hash,
+            size - 1,
+            false,
+        )
+        .await;
+        assert!(
+            matches!(result, Err(FileAcquisitionError::DownloadVerifyError(_, reason)) if reason.contains("bytes"))
+        );
+        assert!(!partial_path(&save_loc).exists());
+        assert!(!save_loc.exists());
+    }
+
+    #[test]
+    fn install_dest_stays_inside() {
+        let root = Path::new("/games/fs2/mod");
+        assert_eq!(
+            install_dest(root, Path::new("data/tables/ships.tbl")).unwrap(),
+            root.join("data/tables/ships.tbl")
+        );
+        assert!(install_dest(root, Path::new("../../../etc/passwd")).is_err());
+        assert!(install_dest(root, Path::new("/etc/passwd")).is_err());
+        assert!(install_dest(root, Path::new("")).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Reading 7z, zip and tar archives.
+//! None of the archive libraries do async reads, so apart from `extract`,
+//! everything here and in the format modules blocks, and should be run with `spawn_blocking`.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use super::readers::ReaderError;
+use crate::common::{Archive, SHA256Checksum, SourceFormat};
+
+pub mod sevenz;
+pub mod tar;
+pub mod zip;
+
+const CHUNK_LEN: usize = 65536;
+
+/// A file found inside an archive.
+pub struct EntryHash {
+    pub name: String,
+    pub hash: SHA256Checksum,
+    // Where it was unpacked to, if it was asked for.
+    pub unpacked: Option<PathBuf>,
+}
+
+/// What sort of archive the file at `path` is, going by its first few bytes.
+/// VPs aren't checked for here, as the indexer validates those properly.
+pub fn archive_format(path: &Path) -> Option<SourceFormat> {
+    if sevenz::is_sevenz(path) {
+        Some(SourceFormat::SevenZip)
+    } else if zip::is_zip(path) {
+        Some(SourceFormat::Zip)
+    } else if tar::is_tar(path) {
+        Some(SourceFormat::Tar)
+    } else {
+        None
+    }
+}
+
+/// What sort of archive entries of a source in this format are recorded as.
+pub fn archive_type(format: SourceFormat) -> Option<Archive> {
+    match format {
+        SourceFormat::VP => Some(Archive::VP),
+        SourceFormat::SevenZip => Some(Archive::SevenZip),
+        SourceFormat::Zip => Some(Archive::Zip),
+        SourceFormat::Tar => Some(Archive::Tar),
+        SourceFormat::Raw => None,
+    }
+}
+
+/// Hash every file in an archive, in one pass through it.
+/// Entries `unpack` gives a path for are written out there too,
+/// for things like VPs that need indexing in turn.
+pub fn hash_entries(
+    format: SourceFormat,
+    file_path: &Path,
+    unpack: impl Fn(&str) -> Option<PathBuf>,
+) -> Result<Vec<EntryHash>, ReaderError> {
+    match format {
+        SourceFormat::SevenZip => sevenz::hash_entries(file_path, unpack),
+        SourceFormat::Zip => zip::hash_entries(file_path, unpack),
+        SourceFormat::Tar => tar::hash_entries(file_path, unpack),
+        SourceFormat::Raw | SourceFormat::VP => Err(not_packed(file_path, format)),
+    }
+}
+
+/// Unpack a whole archive into `dest`.
+pub async fn extract(
+    format: SourceFormat,
+    file_path: &Path,
+    dest: &Path,
+) -> Result<(), ReaderError> {
+    let fp = file_path.to_path_buf();
+    let dest = dest.to_path_buf();
+    // None of the archive libraries do async reads.
+    tokio::task::spawn_blocking(move || match format {
+        SourceFormat::SevenZip => sevenz::extract(&fp, &dest),
+        SourceFormat::Zip => zip::extract(&fp, &dest),
+        SourceFormat::Tar => tar::extract(&fp, &dest),
+        SourceFormat::Raw | SourceFormat::VP => Err(not_packed(&fp, format)),
+    })
+    .await?
+}
+
+fn not_packed(file_path: &Path, format: SourceFormat) -> ReaderError {
+    ReaderError::ArchiveError(
+        file_path.to_path_buf(),
+        format!("{:?} files can't be unpacked", format),
+    )
+}
+
+// Entry names are compared with forward slashes and no leading ./,
+// as Windows tools write backslashes and tar likes to start everything with ./
+fn entry_name(name: &str) -> String {
+    let name = name.replace('\\', "/");
+    name.trim_start_matches("./").to_string()
+}
+
+fn read_magic<const N: usize>(path: &Path) -> Option<[u8; N]> {
+    let mut magic = [0u8; N];
+    File::open(path).ok()?.read_exact(&mut magic).ok()?;
+    Some(magic)
+}
+
+// Stream an entry down the channel a chunk at a time.
+fn send_entry(
+    reader: &mut dyn Read,
+    tx: &mpsc::Sender<Result<Bytes, ReaderError>>,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; CHUNK_LEN];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if tx
+            .blocking_send(Ok(Bytes::copy_from_slice(&buf[..len])))
+            .is_err()
+        {
+            return Ok(()); // Nobody's listening any more, no point reading the rest.
+        }
+    }
+}
+
+// Hash an entry, writing it out to `unpack_to` on the way past if there is one.
+fn hash_entry(reader: &mut dyn Read, unpack_to: Option<&Path>) -> io::Result<SHA256Checksum> {
+    let mut outfile = unpack_to.map(File::create).transpose()?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_LEN];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        if let Some(outfile) = outfile.as_mut() {
+            outfile.write_all(&buf[..len])?;
+        }
+    }
+    Ok(SHA256Checksum(hasher.finalize().into_iter().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type EntryReader =
+        fn(&Path, &str, &mpsc::Sender<Result<Bytes, ReaderError>>) -> Result<(), ReaderError>;
+
+    // Archives made with bsdtar and tar, each holding mv_radaricons.vp and data/hud/radar-asteroid.dds.
+    fn test_file(name: &str) -> PathBuf {
+        Path::new("vp/test_files").join(name)
+    }
+
+    fn read_whole(
+        read_entry: EntryReader,
+        archive: &Path,
+        entry: &str,
+    ) -> Result<Vec<u8>, ReaderError> {
+        // Big enough to hold every chunk of the test files, as nothing reads them until read_entry returns.
+        let (tx, mut rx) = mpsc::channel(64);
+        read_entry(archive, entry, &tx)?;
+        drop(tx);
+        let mut out = Vec::new();
+        while let Some(chunk) = rx.blocking_recv() {
+            out.extend(chunk?);
+        }
+        Ok(out)
+    }
+
+    fn check_read_entry(read_entry: EntryReader, archive: &str) {
+        let archive = test_file(archive);
+        let dds = read_whole(read_entry, &archive, "data/hud/radar-asteroid.dds").unwrap();
+        assert_eq!(dds, std::fs::read(test_file("radar-asteroid.dds")).unwrap());
+        // Either separator, with or without a leading ./
+        let vp = read_whole(read_entry, &archive, ".\\mv_radaricons.vp").unwrap();
+        assert_eq!(vp, std::fs::read(test_file("mv_radaricons.vp")).unwrap());
+        assert!(matches!(
+            read_whole(read_entry, &archive, "data/hud/missing.dds"),
+            Err(ReaderError::EntryError(..))
+        ));
+    }
+
+    // Hash everything in one pass, unpacking the VP so it can be indexed in turn.
+    fn check_hash_entries(format: SourceFormat, archive: &str) {
+        let unpack_dir = tempfile::tempdir().unwrap();
+        let unpack_to = unpack_dir.path().join("0.vp");
+        let mut entries = hash_entries(format, &test_file(archive), |name| {
+            name.ends_with(".vp").then(|| unpack_to.clone())
+        })
+        .unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        // Directories are left out.
+        let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["data/hud/radar-asteroid.dds", "mv_radaricons.vp"]);
+        for (entry, file) in entries
+            .iter()
+            .zip(["radar-asteroid.dds", "mv_radaricons.vp"])
+        {
+            let contents = std::fs::read(test_file(file)).unwrap();
+            assert_eq!(entry.hash.0, Sha256::digest(&contents).to_vec());
+        }
+        assert!(entries[0].unpacked.is_none());
+        assert_eq!(entries[1].unpacked.as_ref(), Some(&unpack_to));
+        assert_eq!(
+            std::fs::read(&unpack_to).unwrap(),
+            std::fs::read(test_file("mv_radaricons.vp")).unwrap()
+        );
+    }
+
+    #[test]
+    fn sevenz_read_entry() {
+        check_read_entry(sevenz::read_entry, "radaricons.7z");
+    }
+
+    #[test]
+    fn sevenz_hash_entries() {
+        check_hash_entries(SourceFormat::SevenZip, "radaricons.7z");
+    }
+
+    #[test]
+    fn zip_entries() {
+        check_read_entry(zip::read_entry, "radaricons.zip");
+        check_hash_entries(SourceFormat::Zip, "radaricons.zip");
+    }
+
+    #[test]
+    fn tar_entries() {
+        for archive in ["radaricons.tar.gz", "radaricons.tar.xz"] {
+            check_read_entry(tar::read_entry, archive);
+            check_hash_entries(SourceFormat::Tar, archive);
+        }
+    }
+
+    #[test]
+    fn sniff_formats() {
+        let format = |name| archive_format(&test_file(name));
+        assert_eq!(format("radaricons.7z"), Some(SourceFormat::SevenZip));
+        assert_eq!(format("radaricons.zip"), Some(SourceFormat::Zip));
+        assert_eq!(format("radaricons.tar.gz"), Some(SourceFormat::Tar));
+        assert_eq!(format("radaricons.tar.xz"), Some(SourceFormat::Tar));
+        assert_eq!(format("mv_radaricons.vp"), None);
+        assert_eq!(format("radar-asteroid.dds"), None);
+        assert_eq!(format("missing.zip"), None);
+    }
+
+    #[tokio::test]
+    async fn extract_archives() {
+        let archives = [
+            (SourceFormat::SevenZip, "radaricons.7z"),
+            (SourceFormat::Zip, "radaricons.zip"),
+            (SourceFormat::Tar, "radaricons.tar.xz"),
+        ];
+        for (format, archive) in archives {
+            let dir = tempfile::tempdir().unwrap();
+            let dest = dir.path().join("extracted");
+            extract(format, &test_file(archive), &dest).await.unwrap();
+            for (path, file) in [
+                ("data/hud/radar-asteroid.dds", "radar-asteroid.dds"),
+                ("mv_radaricons.vp", "mv_radaricons.vp"),
+            ] {
+                assert_eq!(
+                    tokio::fs::read(dest.join(path)).await.unwrap(),
+                    tokio::fs::read(test_file(file)).await.unwrap()
+                );
+            }
+        }
+        // Raw files aren't archives.
+        let raw = test_file("radar-asteroid.dds");
+        let result = extract(SourceFormat::Raw, &raw, Path::new("unused")).await;
+        assert!(matches!(result, Err(ReaderError::ArchiveError(..))));
+    }
+}
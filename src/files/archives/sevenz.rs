@@ -0,0 +1,89 @@
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use sevenz_rust::{decompress_file, SevenZReader};
+use tokio::sync::mpsc;
+
+use super::{entry_name, hash_entry, read_magic, send_entry, EntryHash};
+use crate::files::readers::ReaderError;
+
+/// First bytes of every 7z archive.
+pub const SEVENZ_MAGIC: [u8; 6] = [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+
+pub fn is_sevenz(path: &Path) -> bool {
+    read_magic(path) == Some(SEVENZ_MAGIC)
+}
+
+// Unfortunately due to the library implementation, this relies on blocking read operations.
+pub fn extract(file_path: &Path, dest: &Path) -> Result<(), ReaderError> {
+    decompress_file(file_path, dest).map_err(|e| sz_error(file_path, e))
+}
+
+/// Stream a single entry out of a 7z archive, without unpacking the rest of it to disk.
+/// Archives are usually solid, so entries before ours in its block still get decompressed,
+/// but they're thrown away as we go and blocks after it aren't touched.
+pub fn read_entry(
+    file_path: &Path,
+    entry: &str,
+    tx: &mpsc::Sender<Result<Bytes, ReaderError>>,
+) -> Result<(), ReaderError> {
+    let mut archive =
+        SevenZReader::open(file_path, "".into()).map_err(|e| sz_error(file_path, e))?;
+    let wanted = entry_name(entry);
+    let found = Cell::new(false);
+    archive
+        .for_each_entries(|sz_entry, reader| {
+            if entry_name(sz_entry.name()) != wanted {
+                // Later entries in a solid block carry on from where this one ends.
+                io::copy(reader, &mut io::sink()).map_err(sevenz_rust::Error::io)?;
+                return Ok(true);
+            }
+            found.set(true);
+            send_entry(reader, tx).map_err(sevenz_rust::Error::io)?;
+            Ok(false)
+        })
+        .map_err(|e| sz_error(file_path, e))?;
+    if found.get() {
+        Ok(())
+    } else {
+        Err(ReaderError::EntryError(
+            file_path.to_path_buf(),
+            entry.to_string(),
+        ))
+    }
+}
+
+/// Hash every file in a 7z archive.
+/// It's done in one pass, as reading entries one at a time
+/// would decompress the start of each solid block over and over.
+pub fn hash_entries(
+    file_path: &Path,
+    unpack: impl Fn(&str) -> Option<PathBuf>,
+) -> Result<Vec<EntryHash>, ReaderError> {
+    let mut archive =
+        SevenZReader::open(file_path, "".into()).map_err(|e| sz_error(file_path, e))?;
+    let entries = RefCell::new(Vec::new());
+    archive
+        .for_each_entries(|sz_entry, reader| {
+            if sz_entry.is_directory() {
+                return Ok(true);
+            }
+            let name = entry_name(sz_entry.name());
+            let unpacked = unpack(&name);
+            let hash = hash_entry(reader, unpacked.as_deref()).map_err(sevenz_rust::Error::io)?;
+            entries.borrow_mut().push(EntryHash {
+                name,
+                hash,
+                unpacked,
+            });
+            Ok(true)
+        })
+        .map_err(|e| sz_error(file_path, e))?;
+    Ok(entries.into_inner())
+}
+
+fn sz_error(file_path: &Path, err: sevenz_rust::Error) -> ReaderError {
+    ReaderError::ArchiveError(file_path.to_path_buf(), err.to_string())
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use ::tar::Archive;
+use bytes::Bytes;
+use flate2::read::MultiGzDecoder;
+use tokio::sync::mpsc;
+use xz2::read::XzDecoder;
+
+use super::{entry_name, hash_entry, read_magic, send_entry, EntryHash};
+use crate::files::readers::ReaderError;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0];
+// Tarballs don't have a magic number at the start, but POSIX and GNU ones have one in their first header.
+const USTAR_OFFSET: usize = 257;
+const USTAR_MAGIC: &[u8] = b"ustar";
+
+// Tarballs are usually compressed as a whole, so work out what with and undo it.
+fn decompressed(file_path: &Path) -> io::Result<Box<dyn Read>> {
+    let magic: [u8; 6] = read_magic(file_path).unwrap_or_default();
+    let file = BufReader::new(File::open(file_path)?);
+    Ok(if magic.starts_with(&GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(file))
+    } else if magic == XZ_MAGIC {
+        Box::new(XzDecoder::new(file))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Whether this is a tarball, plain, gzipped or xz compressed.
+pub fn is_tar(path: &Path) -> bool {
+    let mut header = [0u8; USTAR_OFFSET + USTAR_MAGIC.len()];
+    match decompressed(path) {
+        Ok(mut reader) => {
+            reader.read_exact(&mut header).is_ok() && &header[USTAR_OFFSET..] == USTAR_MAGIC
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn extract(file_path: &Path, dest: &Path) -> Result<(), ReaderError> {
+    // Entries that would land outside dest are skipped by the library.
+    Archive::new(decompressed(file_path)?)
+        .unpack(dest)
+        .map_err(|e| tar_error(file_path, e))
+}
+
+/// Stream a single entry out of a tarball.
+/// There's no index, so everything before it gets read through on the way.
+pub fn read_entry(
+    file_path: &Path,
+    entry: &str,
+    tx: &mpsc::Sender<Result<Bytes, ReaderError>>,
+) -> Result<(), ReaderError> {
+    let mut archive = Archive::new(decompressed(file_path)?);
+    let wanted = entry_name(entry);
+    for tar_entry in archive.entries().map_err(|e| tar_error(file_path, e))? {
+        let mut tar_entry = tar_entry.map_err(|e| tar_error(file_path, e))?;
+        if !tar_entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = tar_entry.path().map_err(|e| tar_error(file_path, e))?;
+        if entry_name(&name.to_string_lossy()) == wanted {
+            return send_entry(&mut tar_entry, tx).map_err(|e| tar_error(file_path, e));
+        }
+    }
+    Err(ReaderError::EntryError(
+        file_path.to_path_buf(),
+        entry.to_string(),
+    ))
+}
+
+/// Hash every file in a tarball.
+/// Links and directories are skipped, as they don't have contents of their own.
+pub fn hash_entries(
+    file_path: &Path,
+    unpack: impl Fn(&str) -> Option<PathBuf>,
+) -> Result<Vec<EntryHash>, ReaderError> {
+    let mut archive = Archive::new(decompressed(file_path)?);
+    let mut entries = Vec::new();
+    for tar_entry in archive.entries().map_err(|e| tar_error(file_path, e))? {
+        let mut tar_entry = tar_entry.map_err(|e| tar_error(file_path, e))?;
+        if !tar_entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = tar_entry.path().map_err(|e| tar_error(file_path, e))?;
+        let name = entry_name(&name.to_string_lossy());
+        let unpacked = unpack(&name);
+        let hash =
+            hash_entry(&mut tar_entry, unpacked.as_deref()).map_err(|e| tar_error(file_path, e))?;
+        entries.push(EntryHash {
+            name,
+            hash,
+            unpacked,
+        });
+    }
+    Ok(entries)
+}
+
+// The tar library reports corruption as IO errors, so wrap them up with which archive it was.
+fn tar_error(file_path: &Path, err: io::Error) -> ReaderError {
+    ReaderError::ArchiveError(file_path.to_path_buf(), err.to_string())
+}
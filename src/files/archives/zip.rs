@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use ::zip::{result::ZipError, ZipArchive};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use super::{entry_name, hash_entry, read_magic, send_entry, EntryHash};
+use crate::files::readers::ReaderError;
+
+// Zips start with a local file header, or the end of the central directory if they're empty.
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 3, 4];
+const EMPTY_ZIP_MAGIC: [u8; 4] = [b'P', b'K', 5, 6];
+
+pub fn is_zip(path: &Path) -> bool {
+    matches!(read_magic(path), Some(ZIP_MAGIC | EMPTY_ZIP_MAGIC))
+}
+
+fn open(file_path: &Path) -> Result<ZipArchive<BufReader<File>>, ReaderError> {
+    let file = BufReader::new(File::open(file_path)?);
+    ZipArchive::new(file).map_err(|e| zip_error(file_path, e))
+}
+
+pub fn extract(file_path: &Path, dest: &Path) -> Result<(), ReaderError> {
+    // Entries that would land outside dest are refused by the library.
+    open(file_path)?
+        .extract(dest)
+        .map_err(|e| zip_error(file_path, e))
+}
+
+/// Stream a single entry out of a zip.
+/// Zip entries are compressed separately, so this only reads the one we want.
+pub fn read_entry(
+    file_path: &Path,
+    entry: &str,
+    tx: &mpsc::Sender<Result<Bytes, ReaderError>>,
+) -> Result<(), ReaderError> {
+    let mut archive = open(file_path)?;
+    let wanted = entry_name(entry);
+    // Names are matched by hand, as older zips made on Windows use backslashes.
+    let name = archive
+        .file_names()
+        .find(|name| entry_name(name) == wanted)
+        .map(str::to_string)
+        .ok_or_else(|| ReaderError::EntryError(file_path.to_path_buf(), entry.to_string()))?;
+    let mut zip_file = archive
+        .by_name(&name)
+        .map_err(|e| zip_error(file_path, e))?;
+    send_entry(&mut zip_file, tx)?;
+    Ok(())
+}
+
+/// Hash every file in a zip.
+pub fn hash_entries(
+    file_path: &Path,
+    unpack: impl Fn(&str) -> Option<PathBuf>,
+) -> Result<Vec<EntryHash>, ReaderError> {
+    let mut archive = open(file_path)?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let mut zip_file = archive
+            .by_index(index)
+            .map_err(|e| zip_error(file_path, e))?;
+        if zip_file.is_dir() {
+            continue;
+        }
+        let name = entry_name(zip_file.name());
+        let unpacked = unpack(&name);
+        let hash = hash_entry(&mut zip_file, unpacked.as_deref())?;
+        entries.push(EntryHash {
+            name,
+            hash,
+            unpacked,
+        });
+    }
+    Ok(entries)
+}
+
+fn zip_error(file_path: &Path, err: ZipError) -> ReaderError {
+    ReaderError::ArchiveError(file_path.to_path_buf(), err.to_string())
+}
@@ -5,19 +5,20 @@ use async_channel;
 use tokio::sync::oneshot;
 use tokio::task::JoinSet;
 
-type VecPlusOneshot = (Vec<u8>, oneshot::Sender<Vec<u8>>);
+type PathVecPlusOneshot = (String, Vec<u8>, oneshot::Sender<Vec<u8>>);
+type VecPlusResultOneshot = (Vec<u8>, oneshot::Sender<std::io::Result<Vec<u8>>>);
 
 pub async fn spawn_vp_decompressors(
     queue_len: usize,
     dc_count: Option<usize>,
-) -> (async_channel::Sender<VecPlusOneshot>, JoinSet<()>) {
-    let (tx_uc, rx_uc) = async_channel::bounded::<VecPlusOneshot>(queue_len);
+) -> (async_channel::Sender<VecPlusResultOneshot>, JoinSet<()>) {
+    let (tx_uc, rx_uc) = async_channel::bounded::<VecPlusResultOneshot>(queue_len);
     let threads = dc_count.unwrap_or_else(num_cpus::get);
     let mut decompress_tasks = JoinSet::new();
     for _ in 0..threads {
         let rx = rx_uc.clone();
         decompress_tasks.spawn(async move {
-            // decompression cannot fail, it can only be failed
+            // Corrupt entries are passed back down the oneshot for the requester to deal with.
             while let Ok((entry, os_tx)) = rx.recv().await {
                 // Recieve a vector to be decompressed, and the oneshot for it too.
                 let contents = vp::compression::maybe_decompress(entry);
@@ -31,17 +32,18 @@ pub async fn spawn_vp_decompressors(
 pub async fn spawn_vp_compressors(
     queue_len: usize,
     dc_count: Option<usize>,
-) -> (async_channel::Sender<VecPlusOneshot>, JoinSet<()>) {
-    let (tx_uc, rx_uc) = async_channel::bounded::<VecPlusOneshot>(queue_len);
+) -> (async_channel::Sender<PathVecPlusOneshot>, JoinSet<()>) {
+    let (tx_uc, rx_uc) = async_channel::bounded::<PathVecPlusOneshot>(queue_len);
     let threads = dc_count.unwrap_or_else(num_cpus::get);
     let mut compress_tasks = JoinSet::new();
     for _ in 0..threads {
         let rx = rx_uc.clone();
         compress_tasks.spawn(async move {
-            // decompression cannot fail, it can only be failed
-            while let Ok((entry, os_tx)) = rx.recv().await {
-                // Recieve a vector to be decompressed, and the oneshot for it too.
-                let contents = vp::compression::maybe_decompress(entry);
+            // compression cannot fail, it can only be failed
+            while let Ok((path, entry, os_tx)) = rx.recv().await {
+                // Recieve a vector to be compressed, its path in the VP, and the oneshot for it too.
+                // The path tells us if FSO can read this file compressed.
+                let contents = vp::compression::maybe_compress(&path, entry);
                 os_tx.send(contents).unwrap() // Yeah, again, don't really know what to do if the oneshot sender fails here.
             }
         });
@@ -94,7 +94,7 @@ impl<T: Hash + Eq + Clone, U: Clone> HashDAG<T, U> {
         let chi_opt = self.node_map.get(child);
         let par_opt = self.node_map.get(parent);
         if let (Some(chi_idx), Some(par_idx)) = (chi_opt, par_opt) {
-            self.edge_map.get(&(*chi_idx, *par_idx))
+            self.edge_map.get(&(*par_idx, *chi_idx))
         } else {
             None
         }
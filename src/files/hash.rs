@@ -6,10 +6,12 @@ use crate::common::SHA256Checksum;
 
 use super::readers::ReaderError;
 
-pub async fn hash_channel(mut rx: mpsc::Receiver<Result<Bytes, ReaderError>>) -> SHA256Checksum {
+pub async fn hash_channel(
+    mut rx: mpsc::Receiver<Result<Bytes, ReaderError>>,
+) -> Result<SHA256Checksum, ReaderError> {
     let mut hasher = sha2::Sha256::new();
     while let Some(chunk) = rx.recv().await {
-        hasher.update(chunk.unwrap());
+        hasher.update(chunk?);
     }
-    SHA256Checksum(hasher.finalize().into_iter().collect())
+    Ok(SHA256Checksum(hasher.finalize().into_iter().collect()))
 }
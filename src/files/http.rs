@@ -0,0 +1,82 @@
+use std::io;
+
+use bytes::Bytes;
+use reqwest::{header, Client, StatusCode};
+use vp::range::RangeRead;
+
+/// Reads parts of a remote file with HTTP `Range` requests.
+/// Servers that ignore `Range` give an `Unsupported` error rather than the whole file.
+pub struct HttpRangeReader {
+    client: Client,
+    url: String,
+    size: Option<u64>,
+}
+
+impl HttpRangeReader {
+    pub fn new(client: Client, url: &str) -> Self {
+        HttpRangeReader {
+            client,
+            url: url.to_string(),
+            size: None,
+        }
+    }
+}
+
+impl RangeRead for HttpRangeReader {
+    async fn size(&mut self) -> io::Result<u64> {
+        if let Some(size) = self.size {
+            return Ok(size);
+        }
+        let res = self
+            .client
+            .head(&self.url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(io::Error::other)?;
+        // Not content_length(), that's the length of the body, which a HEAD doesn't have.
+        let size = res
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("{} has no Content-Length", self.url)))?;
+        self.size = Some(size);
+        Ok(size)
+    }
+
+    async fn read_range(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        let last = offset + len as u64 - 1;
+        let res = self
+            .client
+            .get(&self.url)
+            .header(header::RANGE, format!("bytes={}-{}", offset, last))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(io::Error::other)?;
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} doesn't support range requests", self.url),
+            ));
+        }
+        // Every range response tells us the full size, which saves a HEAD later.
+        if let Some(size) = res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| content_range_size(v.to_str().ok()?))
+        {
+            self.size = Some(size);
+        }
+        res.bytes().await.map_err(io::Error::other)
+    }
+}
+
+// `Content-Range: bytes 0-15/12345`, the total can be `*` if the server doesn't know it.
+fn content_range_size(content_range: &str) -> Option<u64> {
+    content_range.rsplit_once('/')?.1.parse().ok()
+}
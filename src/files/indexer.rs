@@ -1,15 +1,25 @@
-use std::{ffi::OsStr, path::Path};
+use std::{
+    cell::Cell,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
 
 use bytes::Bytes;
 use futures::stream::{self, StreamExt};
 use hash_hasher::HashedMap;
 use tokio::sync::mpsc;
-use vp::fs::async_index;
+use vp::{
+    fs::async_index,
+    types::VPParseError,
+    validate::{async_validate, Problem},
+};
 use walkdir::WalkDir;
 
 use super::{
+    archives::{archive_format, archive_type, hash_entries, EntryHash},
     hash::hash_channel,
     readers::{Get, GetRequest, ReaderError},
+    util::get_cs_path,
     DataPath,
 };
 use crate::common::{Archive, ArchiveEntry, Source, SourceFormat};
@@ -27,6 +37,12 @@ pub enum IndexError {
     IOError(std::io::Error),
     #[error("SQL Error: {0}")]
     SqlxError(sqlx::Error),
+    #[error("Could not parse VP: {0}")]
+    VPParseError(VPParseError),
+    #[error("Read Error: {0}")]
+    ReaderError(ReaderError),
+    #[error("VP {0:?} failed validation: {1:?}")]
+    InvalidVP(PathBuf, Vec<Problem>),
 }
 
 impl From<sqlx::Error> for IndexError {
@@ -41,6 +57,18 @@ impl From<std::io::Error> for IndexError {
     }
 }
 
+impl From<VPParseError> for IndexError {
+    fn from(err: VPParseError) -> Self {
+        IndexError::VPParseError(err)
+    }
+}
+
+impl From<ReaderError> for IndexError {
+    fn from(err: ReaderError) -> Self {
+        IndexError::ReaderError(err)
+    }
+}
+
 pub async fn index_dir(
     dir: impl AsRef<Path>,
     state: SolGateState,
@@ -81,7 +109,7 @@ pub async fn index_file(
         .send(GetRequest {
             contents: Get::Path(dp),
             channel: hash_tx,
-            queue: true,
+            raw: false,
         })
         .await
         .expect("Send failed, but it's infallible???");
@@ -89,7 +117,7 @@ pub async fn index_file(
     let hash_jh = tokio::spawn(async move { hash_channel(hash_rx).await });
     // TODO: Find hash_id and commit.
     let mut sql_tx = state.sql_pool.begin().await?;
-    let hash = hash_jh.await.unwrap(); // hash_channel can't panic so unwrap() is ok here
+    let hash = hash_jh.await.unwrap()?; // hash_channel can't panic so unwrap() is ok here
     let hashvec = vec![hash.clone()];
 
     add_hashes(&hashvec, &mut sql_tx).await?;
@@ -100,18 +128,15 @@ pub async fn index_file(
         .1
         .clone();
     let file_format: SourceFormat;
-    let mut vp_jh = None;
-    if path.extension() == Some(&OsStr::new("vp")) {
-        // Index the VP contents too.
-        let path2 = path.clone();
-        vp_jh = Some(tokio::spawn(async move {
-            index_vp(&path2, state.clone(), file_hid).await
-        }));
+    if vp_extension(&path) && readable_vp(&path).await {
         file_format = SourceFormat::VP;
-    } else if path.extension() == Some(&OsStr::new("vpc")) {
-        todo!("Compressed VP handling not implemented yet.")
     } else {
-        file_format = SourceFormat::Raw;
+        // Downloads are saved under their hash, so there's no extension to go by.
+        let magic_path = path.clone();
+        file_format = tokio::task::spawn_blocking(move || archive_format(&magic_path))
+            .await
+            .expect("join failed")
+            .unwrap_or(SourceFormat::Raw);
     }
     let size = tokio::fs::metadata(filepath).await?.len();
     let file_source = Source {
@@ -123,18 +148,64 @@ pub async fn index_file(
     };
 
     let sources = vec![file_source];
-    add_sources(&sources, &mut sql_tx).await;
+    add_sources(&sources, &mut sql_tx).await?;
+    sql_tx.commit().await?;
+    if file_format == SourceFormat::VP {
+        // Index the VP contents too, now the VP itself is committed.
+        index_vp(&path, state, file_hid).await?;
+    } else if file_format != SourceFormat::Raw {
+        index_archive(&path, state, file_hid, file_format).await?;
+    }
     Ok(())
 }
 
+// A corrupt VP is still a file we can serve, we just can't index what's inside it.
+// .vpc files are VPs with LZ41 compressed entries,
+// the reader pool decompresses these for us, so they can be treated the same as VPs.
+fn vp_extension(path: &Path) -> bool {
+    let extension = path.extension().map(OsStr::to_ascii_lowercase);
+    matches!(
+        extension.as_ref().and_then(|e| e.to_str()),
+        Some("vp" | "vpc")
+    )
+}
+
+async fn readable_vp(path: &Path) -> bool {
+    match validate_vp(path).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Indexing {} as a raw file: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+// Flag VPs that would give us bad data, rather than indexing them as a source of their entries.
+// Plenty of released VPs have harmless problems like trailing data, so those are only warned about.
+async fn validate_vp(path: &Path) -> Result<(), IndexError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let (corrupt, harmless): (Vec<Problem>, Vec<Problem>) = async_validate(&mut file)
+        .await?
+        .into_iter()
+        .partition(Problem::corrupts_data);
+    for problem in harmless {
+        eprintln!("Warning: {}: {}", path.display(), problem);
+    }
+    if corrupt.is_empty() {
+        Ok(())
+    } else {
+        Err(IndexError::InvalidVP(path.to_path_buf(), corrupt))
+    }
+}
+
+/// Index everything in a VP, which should already have passed `readable_vp`.
 pub async fn index_vp(
     path: &std::path::PathBuf,
     state: SolGateState,
     vp_hash_id: i64,
 ) -> Result<(), IndexError> {
-    // It's a VP, so we need to index everything in it.
     let mut file = tokio::fs::File::open(path.clone()).await?;
-    let idx = async_index(&mut file).await.unwrap(); //REALLY hope we're indexing a VP.
+    let idx = async_index(&mut file).await?;
     drop(file);
     let vp_entries = idx.flatten();
     let names: Vec<String> = vp_entries
@@ -153,11 +224,11 @@ pub async fn index_vp(
             .send(GetRequest {
                 contents: Get::Path(dp),
                 channel: hash_tx,
-                queue: true,
+                raw: false, // Hash the logical file, so it matches FSN's filelist.
             })
             .await
             .expect("Send failed, but it's infallible???");
-        let hash = hash_channel(hash_rx).await;
+        let hash = hash_channel(hash_rx).await?;
         hashes.push(hash)
     }
     let mut sql_tx = state.sql_pool.begin().await.unwrap();
@@ -175,5 +246,99 @@ pub async fn index_vp(
         })
         .collect::<Vec<ArchiveEntry>>();
     add_archive_entries(&archive_entries, &mut sql_tx).await?;
+    sql_tx.commit().await?;
+    Ok(())
+}
+
+/// Index the contents of a 7z, zip or tarball.
+pub async fn index_archive(
+    path: &Path,
+    state: SolGateState,
+    archive_hash_id: i64,
+    format: SourceFormat,
+) -> Result<(), IndexError> {
+    // We can't read the index of a VP inside an archive without unpacking it,
+    // so they get unpacked alongside our downloads, and indexed as sources in their own right.
+    let temp_dir = state.config.read().await.local_settings.temp_dir.clone();
+    let unpack_dir = temp_dir.join("unpack").join(archive_hash_id.to_string());
+    tokio::fs::create_dir_all(&unpack_dir).await?;
+    let archive_path = path.to_path_buf();
+    let vp_dir = unpack_dir.clone();
+    // None of the archive libraries do async reads.
+    let listed = tokio::task::spawn_blocking(move || {
+        let vp_count = Cell::new(0);
+        hash_entries(format, &archive_path, |name| {
+            vp_extension(Path::new(name)).then(|| {
+                vp_count.set(vp_count.get() + 1);
+                vp_dir.join(format!("{}.vp", vp_count.get()))
+            })
+        })
+    })
+    .await
+    .expect("join failed");
+    let indexed = match listed {
+        Ok(entries) => {
+            let archive_type = archive_type(format).unwrap();
+            add_unpacked_entries(entries, &temp_dir, state, archive_hash_id, archive_type).await
+        }
+        Err(e) => Err(e.into()),
+    };
+    // Anything left here was a duplicate or failed to index.
+    tokio::fs::remove_dir_all(&unpack_dir).await?;
+    indexed
+}
+
+async fn add_unpacked_entries(
+    entries: Vec<EntryHash>,
+    temp_dir: &Path,
+    state: SolGateState,
+    archive_hash_id: i64,
+    archive_type: Archive,
+) -> Result<(), IndexError> {
+    let hashes = entries
+        .iter()
+        .map(|entry| entry.hash.clone())
+        .collect::<Vec<_>>();
+    let mut sql_tx = state.sql_pool.begin().await?;
+    add_hashes(&hashes, &mut sql_tx).await?;
+    let hids = HashedMap::from_iter(get_hash_ids(&hashes, &mut sql_tx).await?);
+    let archive_entries = entries
+        .iter()
+        .map(|entry| ArchiveEntry {
+            file_id: *hids.get(&entry.hash).unwrap(),
+            file_path: entry.name.clone(),
+            archive_id: archive_hash_id,
+            archive_type,
+        })
+        .collect::<Vec<ArchiveEntry>>();
+    add_archive_entries(&archive_entries, &mut sql_tx).await?;
+    sql_tx.commit().await?;
+
+    // Now the nesting is recorded, index the VPs we unpacked.
+    for entry in entries {
+        if let Some(unpacked) = entry.unpacked {
+            let dest = get_cs_path(temp_dir, &entry.hash);
+            if tokio::fs::metadata(&dest).await.is_ok() {
+                continue; // Already unpacked this one from somewhere.
+            }
+            if !readable_vp(&unpacked).await {
+                continue;
+            }
+            tokio::fs::create_dir_all(dest.parent().unwrap()).await?;
+            tokio::fs::rename(&unpacked, &dest).await?;
+            let vp_hid = *hids.get(&entry.hash).unwrap();
+            let vp_source = Source {
+                path: dest.to_string_lossy().to_string(),
+                h_id: vp_hid,
+                size: tokio::fs::metadata(&dest).await?.len().try_into().unwrap(),
+                format: SourceFormat::VP,
+                location: SourceLocation::Temp,
+            };
+            let mut sql_tx = state.sql_pool.begin().await?;
+            add_sources(&vec![vp_source], &mut sql_tx).await?;
+            sql_tx.commit().await?;
+            index_vp(&dest, state.clone(), vp_hid).await?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,284 @@
+use std::time::Duration;
+
+use axum::{extract::State, Json};
+use reqwest::Url;
+use sqlx::SqlitePool;
+
+use super::util::get_urls;
+use super::FileAcquisitionError;
+use crate::common::{SHA256Checksum, Source};
+use crate::config::Config;
+use crate::db::queries::{
+    get_hashes_from_ids, get_mirror_stats, get_sources_from_ids, record_mirror_attempt,
+};
+use crate::db::{MirrorStats, SourceLocation};
+use crate::SolGateState;
+
+// Mirrors we've not downloaded from yet are assumed to be this fast, in bytes per second.
+// It's optimistic, so a new mirror gets tried rather than never catching up with one we know.
+const UNTRIED_THROUGHPUT: f64 = 4.0 * 1024.0 * 1024.0;
+
+/// Mirrors are tracked by host, as every file on a mirror comes from the same server.
+pub fn mirror_host(url: &str) -> String {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => url.to_string(),
+    }
+}
+
+// Roughly how fast we'd expect a download from this mirror to go, allowing for failures.
+fn score(stats: Option<&MirrorStats>) -> f64 {
+    match stats {
+        // Each counts as one success and one failure to start with,
+        // so a single failure doesn't rule a mirror out for good.
+        Some(stats) => {
+            let success_rate =
+                (stats.successes + 1) as f64 / (stats.successes + stats.failures + 2) as f64;
+            success_rate * stats.throughput.unwrap_or(UNTRIED_THROUGHPUT)
+        }
+        None => 0.5 * UNTRIED_THROUGHPUT,
+    }
+}
+
+/// Every remote copy of what `source` points at, best mirror first.
+/// Sources the solver picked between are all one mirror's copy of the same file,
+/// so any of them will do, as will any gate or FSN repo that has it.
+pub async fn rank_mirrors(
+    state: &SolGateState,
+    source: &Source,
+) -> Result<Vec<Source>, FileAcquisitionError> {
+    let mut tx = state.sql_pool.begin().await?;
+    let copies = get_sources_from_ids(&vec![source.h_id], &mut tx).await?;
+    let hashes = get_hashes_from_ids(&vec![source.h_id], &mut tx).await?;
+    let stats = get_mirror_stats(&mut tx).await?;
+    tx.commit().await?;
+    let mut mirrors = vec![source.clone()];
+    mirrors.extend(copies.into_iter().filter(|copy| {
+        !copy.location.is_local() && copy.format == source.format && copy.path != source.path
+    }));
+    if let Some(hash) = hashes.first() {
+        let by_hash = hashed_mirrors(&*state.config.read().await, source, &hash.val);
+        for mirror in by_hash {
+            if !mirrors.iter().any(|m| m.path == mirror.path) {
+                mirrors.push(mirror);
+            }
+        }
+    }
+    Ok(rank(mirrors, &stats))
+}
+
+// Gates and FSN repos serve files by their hash, so they're worth trying
+// even when we've no record of them having this one.
+fn hashed_mirrors(config: &Config, source: &Source, hash: &SHA256Checksum) -> Vec<Source> {
+    [SourceLocation::SolGate, SourceLocation::FSN]
+        .into_iter()
+        .flat_map(|location| {
+            // An error only means there aren't any of this sort configured.
+            get_urls(config, &location, hash)
+                .unwrap_or_default()
+                .into_iter()
+                .map(move |path| Source {
+                    location,
+                    path,
+                    ..source.clone()
+                })
+        })
+        .collect()
+}
+
+// Sort mirrors best first, going by how downloads from their hosts have gone.
+fn rank(mirrors: Vec<Source>, stats: &[MirrorStats]) -> Vec<Source> {
+    let scores = mirrors
+        .iter()
+        .map(|mirror| {
+            let host = mirror_host(&mirror.path);
+            score(stats.iter().find(|s| s.host == host))
+        })
+        .collect::<Vec<_>>();
+    let mut ranked = mirrors.into_iter().zip(scores).collect::<Vec<_>>();
+    // Stable, so mirrors we know nothing about stay in the order we were given them.
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranked.into_iter().map(|(mirror, _)| mirror).collect()
+}
+
+/// Whether it's worth trying another mirror after this error, or if they'd all fail the same way.
+pub fn mirror_failed(err: &FileAcquisitionError) -> bool {
+    matches!(
+        err,
+        FileAcquisitionError::NetworkError(_)
+            | FileAcquisitionError::DownloadVerifyError(..)
+            | FileAcquisitionError::SlowDownload(_)
+    )
+}
+
+/// Add how a download went to its mirror's stats.
+/// Stats only decide which mirror gets tried first, so failing to save them is just logged.
+pub async fn record_attempt(
+    state: &SolGateState,
+    url: &str,
+    result: &Result<(), FileAcquisitionError>,
+    bytes: u64,
+    elapsed: Duration,
+) {
+    if let Err(e) = save_attempt(state, url, result, bytes, elapsed).await {
+        eprintln!("Couldn't save stats for {}: {}", mirror_host(url), e);
+    }
+}
+
+async fn save_attempt(
+    state: &SolGateState,
+    url: &str,
+    result: &Result<(), FileAcquisitionError>,
+    bytes: u64,
+    elapsed: Duration,
+) -> Result<(), sqlx::Error> {
+    // Too short to say anything about the mirror's speed.
+    let throughput = (bytes > 0 && elapsed > Duration::from_millis(500))
+        .then(|| bytes as f64 / elapsed.as_secs_f64());
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let mut tx = state.sql_pool.begin().await?;
+    record_mirror_attempt(
+        &mirror_host(url),
+        result.is_ok(),
+        bytes.try_into().unwrap_or(i64::MAX),
+        throughput,
+        error.as_deref(),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await
+}
+
+pub async fn mirror_stats(
+    State(pool): State<SqlitePool>,
+) -> Result<Json<Vec<MirrorStats>>, String> {
+    let mut tx = pool.begin().await.map_err(|x| x.to_string())?;
+    let stats = get_mirror_stats(&mut tx).await.map_err(|x| x.to_string())?;
+    Ok(Json(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SourceFormat;
+    use crate::config::Gate;
+
+    fn mirror(host: &str) -> Source {
+        Source {
+            location: SourceLocation::FSN,
+            path: format!("https://{}/files/ab/cd/abcd.7z", host),
+            h_id: 1,
+            size: 1000,
+            format: SourceFormat::SevenZip,
+        }
+    }
+
+    fn stats(host: &str, successes: i64, failures: i64, throughput: Option<f64>) -> MirrorStats {
+        MirrorStats {
+            host: host.to_string(),
+            successes,
+            failures,
+            bytes: 0,
+            throughput,
+            last_error: None,
+            last_used: None,
+        }
+    }
+
+    fn ranked_hosts(hosts: &[&str], stats: &[MirrorStats]) -> Vec<String> {
+        let mirrors = hosts.iter().map(|host| mirror(host)).collect();
+        rank(mirrors, stats)
+            .iter()
+            .map(|m| mirror_host(&m.path))
+            .collect()
+    }
+
+    #[test]
+    fn mirror_hosts() {
+        assert_eq!(
+            mirror_host("https://cf.fsnebula.org/storage/a.7z"),
+            "cf.fsnebula.org"
+        );
+        assert_eq!(
+            mirror_host("http://192.168.1.5:4000/a.vp"),
+            "192.168.1.5:4000"
+        );
+        assert_eq!(mirror_host("not a url"), "not a url");
+    }
+
+    #[test]
+    fn failed_mirrors_demoted() {
+        let hosts = ["a.example", "b.example", "c.example"];
+        // Untried mirrors keep the order they came in.
+        assert_eq!(ranked_hosts(&hosts, &[]), hosts);
+        let failed = [stats("a.example", 0, 3, None)];
+        assert_eq!(
+            ranked_hosts(&hosts, &failed),
+            ["b.example", "c.example", "a.example"]
+        );
+        // One failure among plenty of successes doesn't rule a mirror out.
+        let mostly_fine = [stats("a.example", 20, 1, Some(8.0 * 1024.0 * 1024.0))];
+        assert_eq!(ranked_hosts(&hosts, &mostly_fine)[0], "a.example");
+    }
+
+    #[test]
+    fn faster_mirrors_first() {
+        let hosts = ["slow.example", "fast.example", "new.example"];
+        let mib = 1024.0 * 1024.0;
+        let known = [
+            stats("slow.example", 10, 0, Some(0.5 * mib)),
+            stats("fast.example", 10, 0, Some(10.0 * mib)),
+        ];
+        // New mirrors get a go before ones we know are slow.
+        assert_eq!(
+            ranked_hosts(&hosts, &known),
+            ["fast.example", "new.example", "slow.example"]
+        );
+        assert!(score(Some(&known[1])) > score(None));
+        assert!(score(None) > score(Some(&known[0])));
+    }
+
+    #[test]
+    fn gates_and_repos_by_hash() {
+        let mut config = Config::default();
+        config.gates.push(Gate {
+            url: "http://192.168.1.5:4000".to_string(),
+            ..Default::default()
+        });
+        let source = mirror("a.example");
+        let mut hash = vec![0x0a, 0xbc];
+        hash.extend([0xff; 30]);
+        let paths = hashed_mirrors(&config, &source, &SHA256Checksum(hash))
+            .into_iter()
+            .map(|m| {
+                assert_eq!(
+                    (m.h_id, m.size, m.format),
+                    (1, 1000, SourceFormat::SevenZip)
+                );
+                (m.location, m.path)
+            })
+            .collect::<Vec<_>>();
+        let hashpath = format!("0a/bc/{}", "ff".repeat(30));
+        assert_eq!(
+            paths[0],
+            (
+                SourceLocation::SolGate,
+                format!("http://192.168.1.5:4000/{}", hashpath)
+            )
+        );
+        // Files are stored next to each repo's repo.json.
+        assert_eq!(
+            paths[1],
+            (
+                SourceLocation::FSN,
+                format!("https://cf.fsnebula.org/storage/{}", hashpath)
+            )
+        );
+        assert_eq!(paths.len(), 1 + config.fsnebula.repos.len());
+    }
+}
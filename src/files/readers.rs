@@ -1,23 +1,24 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::db;
 use db::queries;
 use futures::StreamExt;
 use sqlx::pool::PoolConnection;
 use sqlx::Acquire;
-use vp::{self, types::VPFile};
+use vp::{self, reader::VPArchive};
 
+use super::archives::{sevenz, tar, zip};
 use super::DataPath;
 use bytes::Bytes;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 use tokio_util::io::ReaderStream;
 
 use crate::common::{Archive, SHA256Checksum};
-use rand::seq::SliceRandom;
 
 #[derive(Debug)]
 pub enum Get {
@@ -29,20 +30,7 @@ pub enum Get {
 pub struct GetRequest {
     pub contents: Get,
     pub channel: mpsc::Sender<Result<Bytes, ReaderError>>,
-    pub queue: bool, // If resource handler should prefer queueing requests or grabbing immediately.
-}
-pub enum VPRequestMsg {
-    Read(String, mpsc::Sender<Result<Bytes, ReaderError>>),
-    Exit(),
-}
-
-impl Debug for VPRequestMsg {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Read(string, _stream) => f.debug_tuple("Read").field(string).finish(),
-            Self::Exit() => f.debug_tuple("Exit").finish(),
-        }
-    }
+    pub raw: bool, // Send VP entries as stored, without LZ41 decompression.
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -55,6 +43,12 @@ pub enum ReaderError {
     LocateError(SHA256Checksum),
     #[error("Could not locate file {1} in {0}")]
     VPError(PathBuf, String),
+    #[error("Could not parse VP {0}: {1}")]
+    VPParseError(PathBuf, vp::types::VPParseError),
+    #[error("Could not locate file {1} in {0}")]
+    EntryError(PathBuf, String),
+    #[error("Could not read archive {0}: {1}")]
+    ArchiveError(PathBuf, String),
     #[error("Tokio Runtime Error: {0}")]
     JoinError(tokio::task::JoinError),
 }
@@ -78,21 +72,29 @@ impl From<tokio::task::JoinError> for ReaderError {
 pub struct ReaderPoolActor {
     receiver: mpsc::Receiver<GetRequest>,
     sql_conn: PoolConnection<sqlx::Sqlite>,
-    vp_channels: HashMap<PathBuf, Vec<VPReadHandle>>,
+    open_vps: HashMap<PathBuf, OpenVP>,
 }
 
+// A VP we've read from recently, shared by every request for its entries.
+struct OpenVP {
+    archive: Arc<VPArchive>,
+    // So we notice if the VP gets replaced on disk.
+    modified: Option<SystemTime>,
+    last_used: Instant,
+}
+
+// How long to keep a VP open after its last request.
+const VP_IDLE: Duration = Duration::from_secs(30);
+
 impl ReaderPoolActor {
     pub fn new(
         sql_conn: PoolConnection<sqlx::Sqlite>,
         receiver: mpsc::Receiver<GetRequest>,
     ) -> Self {
-        // Maintain a map of open connections to each of the tasks,
-        let vp_channels = HashMap::<PathBuf, Vec<VPReadHandle>>::new();
-
         ReaderPoolActor {
             receiver,
             sql_conn,
-            vp_channels,
+            open_vps: HashMap::new(),
         }
     }
     async fn handle_msg(&mut self, get_request: GetRequest) {
@@ -113,62 +115,113 @@ impl ReaderPoolActor {
                         tokio::spawn(get_file(fp, get_request.channel));
                     }
                     DataPath::VPEntry(fp, entry) => {
-                        let channels = self.vp_channels.get_mut(&fp).unwrap();
-                        // Clean up closed channels.
-                        channels.retain(|c| !c.tx.is_closed());
-
-                        if get_request.queue && !channels.is_empty() {
-                            // Queue on random existing vec.
-                            let handle = channels.choose(&mut rand::thread_rng()).unwrap();
-                            handle
-                                .tx
-                                .send(VPRequestMsg::Read(entry, get_request.channel))
-                                .await
-                                .unwrap();
-                        } else {
-                            // Otherwise make an new readhandle.
-                            let handle = VPReadHandle::new(fp).await;
-                            handle
-                                .tx
-                                .send(VPRequestMsg::Read(entry, get_request.channel))
-                                .await
-                                .unwrap();
-                            channels.push(handle)
-                        }
+                        let archive = match self.open_vp(fp).await {
+                            Ok(archive) => archive,
+                            // Corrupt or missing VP, let the requester deal with it.
+                            Err(e) => {
+                                let _ = get_request.channel.send(Err(e)).await;
+                                return;
+                            }
+                        };
+                        // Reading from the VP blocks, so keep it off the async threads.
+                        tokio::task::spawn_blocking(move || {
+                            read_vp_entry(archive, entry, get_request.raw, get_request.channel)
+                        });
+                    }
+                    DataPath::SZEntry(fp, entry) => {
+                        spawn_entry_read(sevenz::read_entry, fp, entry, get_request.channel);
+                    }
+                    DataPath::ZipEntry(fp, entry) => {
+                        spawn_entry_read(zip::read_entry, fp, entry, get_request.channel);
+                    }
+                    DataPath::TarEntry(fp, entry) => {
+                        spawn_entry_read(tar::read_entry, fp, entry, get_request.channel);
                     }
-                    DataPath::SZEntry(_fp, _entry) => unimplemented!(),
                 }
             }
         }
     }
 
+    // Any number of reads can share one open VP, so reuse it unless it's changed on disk.
+    // VPs are read with pread rather than mmap, as something else rewriting a VP
+    // in the game directory while it's mapped would crash us.
+    async fn open_vp(&mut self, path: PathBuf) -> Result<Arc<VPArchive>, ReaderError> {
+        let now = Instant::now();
+        self.close_idle_vps();
+        let modified = tokio::fs::metadata(&path).await?.modified().ok();
+        if let Some(vp) = self.open_vps.get_mut(&path) {
+            if vp.modified == modified {
+                vp.last_used = now;
+                return Ok(vp.archive.clone());
+            }
+        }
+
+        let open_path = path.clone();
+        let archive = tokio::task::spawn_blocking(move || VPArchive::open(open_path))
+            .await?
+            .map_err(|e| ReaderError::VPParseError(path.clone(), e))?;
+        let archive = Arc::new(archive);
+        self.open_vps.insert(
+            path,
+            OpenVP {
+                archive: archive.clone(),
+                modified,
+                last_used: now,
+            },
+        );
+        Ok(archive)
+    }
+
+    // Reads still going keep their own reference, so this only closes VPs nobody's using.
+    fn close_idle_vps(&mut self) {
+        let now = Instant::now();
+        self.open_vps
+            .retain(|_, vp| now.duration_since(vp.last_used) < VP_IDLE);
+    }
+
     async fn get_path(&mut self, checksum: &SHA256Checksum) -> Result<DataPath, ReaderError> {
         let sql_res = self.sql_conn.begin().await;
         // Really hope this doesn't fail lol
         let mut sql_tx = sql_res?;
         // We've got a transaction!
         // Get a set of valid DataPaths for the checksum we want.
-        let direct_sources = queries::get_sources_from_hash(&checksum, &mut sql_tx).await?;
+        // Only local sources can be read, remote ones need fetching first.
+        let direct_sources = queries::get_sources_from_hash(&checksum, &mut sql_tx)
+            .await?
+            .into_iter()
+            .filter(|s| s.location.is_local())
+            .collect::<Vec<_>>();
 
         if direct_sources.is_empty() {
-            // We need to find a VP containing our file.
+            // We need to find an archive containing our file.
             let h_id = queries::get_id_from_hash(&checksum, &mut sql_tx).await?;
             let hid_vec = vec![h_id];
             let parents = queries::get_parents_from_ids(&hid_vec, &mut sql_tx).await?;
             let parent_map = parents
                 .into_iter()
-                .filter(|ae| ae.archive_type == Archive::VP) // Don't know how to extract from 7z yet.
-                .map(|p| (p.archive_id, p.file_path))
-                .collect::<HashMap<i64, String>>();
+                .map(|p| (p.archive_id, (p.archive_type, p.file_path)))
+                .collect::<HashMap<i64, (Archive, String)>>();
             let parent_ids = parent_map.keys().cloned().collect();
             let sources = queries::get_sources_from_ids(&parent_ids, &mut sql_tx).await?;
-            let best_source = sources.iter().min().unwrap();
-            let inner_path = parent_map.get(&best_source.h_id).unwrap();
+            // Prefer VPs and zips, as their entries can be read directly,
+            // whereas a 7z or tar entry usually means decompressing everything before it too.
+            let (archive_type, inner_path, best_source) = sources
+                .iter()
+                .filter(|s| s.location.is_local())
+                .map(|s| {
+                    let (archive_type, inner_path) = parent_map.get(&s.h_id).unwrap();
+                    (archive_type, inner_path, s)
+                })
+                .min()
+                .ok_or_else(|| ReaderError::LocateError(checksum.clone()))?;
+            let archive_path = best_source.path.clone().into();
 
-            Ok(DataPath::VPEntry(
-                best_source.path.clone().into(),
-                inner_path.clone(),
-            ))
+            Ok(match archive_type {
+                Archive::VP => DataPath::VPEntry(archive_path, inner_path.clone()),
+                Archive::Zip => DataPath::ZipEntry(archive_path, inner_path.clone()),
+                Archive::SevenZip => DataPath::SZEntry(archive_path, inner_path.clone()),
+                Archive::Tar => DataPath::TarEntry(archive_path, inner_path.clone()),
+            })
         } else {
             // If a direct source exists, just use that.
             // Sort first so we prefer reading from tempdir. it's more likely to be stored on an SSD
@@ -183,8 +236,17 @@ impl ReaderPoolActor {
 // but the ownership model can trip new coders up as it needs (mut self) not (&mut self).
 // see Alice Rhyl's material on Actors in Tokio.
 async fn run_reader_pool(mut pool: ReaderPoolActor) {
-    while let Some(msg) = pool.receiver.recv().await {
-        pool.handle_msg(msg).await
+    // Check for idle VPs on a timer too, so they get closed even if no more requests come in.
+    let mut idle_check = tokio::time::interval(VP_IDLE);
+    idle_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            msg = pool.receiver.recv() => match msg {
+                Some(msg) => pool.handle_msg(msg).await,
+                None => break,
+            },
+            _ = idle_check.tick() => pool.close_idle_vps(),
+        }
     }
 }
 
@@ -221,101 +283,55 @@ pub async fn get_file(filepath: PathBuf, tx: mpsc::Sender<Result<Bytes, ReaderEr
     }
 }
 
-struct VPReadActor {
-    vp: tokio::fs::File,
-    path_map: HashMap<String, VPFile>,
-    receiver: mpsc::Receiver<VPRequestMsg>,
-}
-
-impl VPReadActor {
-    const BUFSIZE: usize = 4096; // same as ReaderStream.
-    async fn new(file_path: impl AsRef<Path>, rx: mpsc::Receiver<VPRequestMsg>) -> Self {
-        let mut vp = tokio::fs::File::open(file_path).await.unwrap();
-        let index = vp::fs::async_index(&mut vp).await.unwrap();
-
-        let path_map = HashMap::<String, VPFile>::from_iter(
-            index
-                .flatten()
-                .into_iter()
-                .map(|vpf| (vpf.name.clone(), vpf)),
-        );
+type EntryReader =
+    fn(&Path, &str, &mpsc::Sender<Result<Bytes, ReaderError>>) -> Result<(), ReaderError>;
 
-        VPReadActor {
-            vp,
-            path_map,
-            receiver: rx,
+// Same as VPs, the archive libraries only do blocking reads.
+fn spawn_entry_read(
+    read_entry: EntryReader,
+    archive: PathBuf,
+    entry: String,
+    tx: mpsc::Sender<Result<Bytes, ReaderError>>,
+) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = read_entry(&archive, &entry, &tx) {
+            let _ = tx.blocking_send(Err(e));
         }
-    }
-
-    async fn handle_msg(&mut self, request: VPRequestMsg) {
-        match request {
-            VPRequestMsg::Exit() => return, // We've been told to quit, so do so.
-            VPRequestMsg::Read(path, tx) => {
-                let vpfile = self.path_map.get(&path).unwrap();
-                self.vp
-                    .seek(SeekFrom::Start(vpfile.fileoffset))
-                    .await
-                    .unwrap();
-                // TODO: Check for compression
-                // We're basically manually doing a ReaderStream thing here
-                // as it means we don't have ownership issues over vp
-                // Also we can bound how big our read is too.
-                let size: usize = vpfile.size.try_into().unwrap();
-                let mut buf = Box::new([0u8; Self::BUFSIZE]);
-                let mut readlen: usize = 0;
-                // Read BUFSIZE, or if there's less than that remaining, read that many bytes.
-                let mut readsize = std::cmp::min(size - readlen, Self::BUFSIZE);
-                loop {
-                    let read_result = self.vp.read(&mut (buf[..readsize])).await;
-                    match read_result {
-                        Ok(len) => {
-                            tx.send(Ok(Bytes::copy_from_slice(&buf[..len])))
-                                .await
-                                .unwrap();
-                            readlen += len;
-                            // Read BUFSIZE, or if there's less than that remaining, read that.
-                            readsize = std::cmp::min(size - readlen, Self::BUFSIZE);
-                            if readsize == 0 {
-                                return;
-                            }
-                        }
-                        Err(e) => {
-                            tx.send(Err(e.into())).await.unwrap();
-                            return;
-                        }
-                    };
-                }
-            }
-        }
-    }
+    });
 }
 
-// We keep this as a seperate function - it could be a method on ReaderPoolActor,
-// but the ownership model can trip new coders up as it needs (mut self) not (&mut self).
-// see Alice Rhyl's material on Actors in Tokio.
-async fn run_vp_reader(mut vp_reader: VPReadActor) {
-    use tokio::time::{timeout, Duration};
-    let duration = Duration::from_millis(500); // Hang around for 500ms
-                                               // If we don't get a new request after 500ms, close the
-    while let Ok(Some(msg)) = timeout(duration, vp_reader.receiver.recv()).await {
-        match msg {
-            VPRequestMsg::Exit() => break, // We've been told to quit, so do so.
-            _ => (),
+fn read_vp_entry(
+    archive: Arc<VPArchive>,
+    entry: String,
+    raw: bool,
+    tx: mpsc::Sender<Result<Bytes, ReaderError>>,
+) {
+    let file = match archive.entry(&entry) {
+        Some(file) => file,
+        None => {
+            let err = ReaderError::VPError(archive.path().to_path_buf(), entry);
+            let _ = tx.blocking_send(Err(err));
+            return;
         }
-        vp_reader.handle_msg(msg).await
+    };
+    let result = if raw {
+        send_chunks(archive.raw_chunks(file), &tx)
+    } else {
+        archive.chunks(file).and_then(|c| send_chunks(c, &tx))
+    };
+    if let Err(e) = result {
+        let _ = tx.blocking_send(Err(e.into()));
     }
 }
-#[derive(Debug, Clone)]
-pub struct VPReadHandle {
-    pub tx: mpsc::Sender<VPRequestMsg>,
-}
 
-impl VPReadHandle {
-    async fn new(file_path: impl AsRef<Path>) -> Self {
-        let (send, rec) = mpsc::channel(8);
-        let actor = VPReadActor::new(file_path, rec).await;
-        tokio::spawn(run_vp_reader(actor));
-
-        VPReadHandle { tx: send }
+fn send_chunks(
+    chunks: impl Iterator<Item = std::io::Result<Bytes>>,
+    tx: &mpsc::Sender<Result<Bytes, ReaderError>>,
+) -> std::io::Result<()> {
+    for chunk in chunks {
+        if tx.blocking_send(Ok(chunk?)).is_err() {
+            break; // Nobody's listening any more, no point reading the rest.
+        }
     }
+    Ok(())
 }
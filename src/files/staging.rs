@@ -0,0 +1,320 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::db::queries::set_mod_installed;
+
+// What an install has done so far, kept on disk so it can be undone if we're interrupted.
+#[derive(Serialize, Deserialize, Debug)]
+struct Journal {
+    name: String,
+    version: String,
+    target: PathBuf,
+    stage: PathBuf,
+    // Where the version we're replacing goes while we swap, if there is one.
+    backup: Option<PathBuf>,
+    committed: bool,
+}
+
+/// A mod being installed into a staging directory, then swapped into place in one go.
+/// Until it's committed, rolling back leaves whatever was installed before untouched.
+pub struct InstallJob {
+    dir: PathBuf,
+    journal: Journal,
+}
+
+impl InstallJob {
+    /// Start installing `name` `version` to `target`, somewhere under `install_dir`.
+    pub async fn begin(
+        temp_dir: &Path,
+        install_dir: &Path,
+        target: PathBuf,
+        name: &str,
+        version: &str,
+    ) -> io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let id = format!("{}-{}-{}", name, version, nanos);
+        let dir = jobs_dir(temp_dir).join(&id);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::create_dir_all(install_dir).await?;
+        // The swap is a rename, which can't cross filesystems,
+        // so if the temp dir is on another drive we stage next to the install instead.
+        let stage = if same_filesystem(&dir, install_dir).await? {
+            dir.join("stage")
+        } else {
+            install_dir.join(".staging").join(&id)
+        };
+        tokio::fs::create_dir_all(&stage).await?;
+        let backup = match tokio::fs::metadata(&target).await {
+            Ok(_) => {
+                let mut backup = target.clone().into_os_string();
+                backup.push(format!(".old-{}", nanos));
+                Some(PathBuf::from(backup))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let job = InstallJob {
+            dir,
+            journal: Journal {
+                name: name.to_string(),
+                version: version.to_string(),
+                target,
+                stage,
+                backup,
+                committed: false,
+            },
+        };
+        job.write_journal().await?;
+        Ok(job)
+    }
+
+    /// Where to put the files, laid out as they'll be installed.
+    pub fn stage(&self) -> &Path {
+        &self.journal.stage
+    }
+
+    /// Move the staged files into place, keeping any previous install until we commit.
+    pub async fn swap(&self) -> io::Result<()> {
+        if let Some(parent) = self.journal.target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if let Some(backup) = &self.journal.backup {
+            tokio::fs::rename(&self.journal.target, backup).await?;
+        }
+        tokio::fs::rename(&self.journal.stage, &self.journal.target).await
+    }
+
+    /// The install is done, so the previous version and the job can go.
+    pub async fn commit(mut self) -> io::Result<()> {
+        self.journal.committed = true;
+        self.write_journal().await?;
+        finish(&self.dir, &self.journal).await
+    }
+
+    /// Put back whatever was installed before we started.
+    pub async fn rollback(self) -> io::Result<()> {
+        undo(&self.journal).await?;
+        finish(&self.dir, &self.journal).await
+    }
+
+    async fn write_journal(&self) -> io::Result<()> {
+        let journal = serde_json::to_vec(&self.journal)?;
+        tokio::fs::write(self.dir.join("journal.json"), journal).await
+    }
+}
+
+/// Roll back any installs that were interrupted, and finish off any that committed.
+/// Fresh installs that get rolled back are marked as not installed,
+/// in case we stopped after the database was updated but before the journal was.
+pub async fn recover_installs(temp_dir: &Path, pool: &SqlitePool) -> io::Result<()> {
+    let mut jobs = match tokio::fs::read_dir(jobs_dir(temp_dir)).await {
+        Ok(jobs) => jobs,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(job) = jobs.next_entry().await? {
+        let dir = job.path();
+        let journal = match tokio::fs::read(dir.join("journal.json")).await {
+            Ok(journal) => serde_json::from_slice::<Journal>(&journal)?,
+            // Interrupted before anything was staged.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                tokio::fs::remove_dir_all(&dir).await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if !journal.committed {
+            eprintln!(
+                "Rolling back interrupted install of {} {}",
+                journal.name, journal.version
+            );
+            undo(&journal).await?;
+            if journal.backup.is_none() {
+                let mut tx = pool.begin().await.map_err(io::Error::other)?;
+                set_mod_installed(&journal.name, &journal.version, false, &mut tx)
+                    .await
+                    .map_err(io::Error::other)?;
+                tx.commit().await.map_err(io::Error::other)?;
+            }
+        }
+        finish(&dir, &journal).await?;
+    }
+    Ok(())
+}
+
+fn jobs_dir(temp_dir: &Path) -> PathBuf {
+    temp_dir.join("installs")
+}
+
+// Works out how far the swap got from what's on disk, and reverses it.
+async fn undo(journal: &Journal) -> io::Result<()> {
+    match &journal.backup {
+        // The previous install was moved aside, so anything at the target is ours.
+        Some(backup) if exists(backup).await? => {
+            remove_dir(&journal.target).await?;
+            tokio::fs::rename(backup, &journal.target).await
+        }
+        // The previous install was never moved, so it's still at the target.
+        Some(_) => Ok(()),
+        // Nothing was installed before, so anything at the target is ours.
+        None => remove_dir(&journal.target).await,
+    }
+}
+
+async fn finish(dir: &Path, journal: &Journal) -> io::Result<()> {
+    if let Some(backup) = &journal.backup {
+        remove_dir(backup).await?;
+    }
+    remove_dir(&journal.stage).await?;
+    remove_dir(dir).await
+}
+
+async fn exists(path: &Path) -> io::Result<bool> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+async fn remove_dir(path: &Path) -> io::Result<()> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Renaming a file between the two is the only portable way to tell.
+async fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    let probe = a.join(".probe");
+    tokio::fs::write(&probe, b"").await?;
+    let moved = b.join(format!(
+        ".probe-{}",
+        a.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let same = match tokio::fs::rename(&probe, &moved).await {
+        Ok(()) => {
+            tokio::fs::remove_file(&moved).await?;
+            true
+        }
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            tokio::fs::remove_file(&probe).await?;
+            false
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(same)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // A fresh directory with a previous install of one file at `mod`.
+    async fn setup(installed: bool) -> (TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let temp_dir = root.path().join("temp");
+        let install_dir = root.path().join("install");
+        let target = install_dir.join("mod");
+        if installed {
+            tokio::fs::create_dir_all(&target).await.unwrap();
+            tokio::fs::write(target.join("old.tbl"), b"old")
+                .await
+                .unwrap();
+        }
+        (root, temp_dir, target)
+    }
+
+    async fn stage_new(temp_dir: &Path, target: &Path) -> InstallJob {
+        let install_dir = target.parent().unwrap();
+        let job = InstallJob::begin(temp_dir, install_dir, target.to_path_buf(), "mod", "1.0")
+            .await
+            .unwrap();
+        tokio::fs::write(job.stage().join("new.tbl"), b"new")
+            .await
+            .unwrap();
+        job
+    }
+
+    async fn installed_files(target: &Path) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut dir = tokio::fs::read_dir(target).await.unwrap();
+        while let Some(entry) = dir.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        names
+    }
+
+    async fn no_jobs_left(temp_dir: &Path, target: &Path) {
+        assert!(installed_files(&jobs_dir(temp_dir)).await.is_empty());
+        let install_dir = installed_files(target.parent().unwrap()).await;
+        assert_eq!(install_dir, vec!["mod".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn recover_partial_swap() {
+        let (_root, temp_dir, target) = setup(true).await;
+        let job = stage_new(&temp_dir, &target).await;
+        // Stopped after moving the old install aside, but before the new one went in.
+        let backup = job.journal.backup.clone().unwrap();
+        tokio::fs::rename(&target, &backup).await.unwrap();
+        drop(job);
+
+        // Updates don't touch the database when they roll back, so it's never connected to.
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        recover_installs(&temp_dir, &pool).await.unwrap();
+        assert_eq!(installed_files(&target).await, vec!["old.tbl".to_string()]);
+        no_jobs_left(&temp_dir, &target).await;
+    }
+
+    #[tokio::test]
+    async fn rollback_after_swap() {
+        let (root, temp_dir, target) = setup(true).await;
+        let job = stage_new(&temp_dir, &target).await;
+        job.swap().await.unwrap();
+        assert_eq!(installed_files(&target).await, vec!["new.tbl".to_string()]);
+        job.rollback().await.unwrap();
+        assert_eq!(installed_files(&target).await, vec!["old.tbl".to_string()]);
+        no_jobs_left(&temp_dir, &target).await;
+
+        // With nothing installed before, rolling back leaves nothing behind.
+        let job = stage_new(&temp_dir, &root.path().join("install/fresh")).await;
+        job.swap().await.unwrap();
+        job.rollback().await.unwrap();
+        assert!(!exists(&root.path().join("install/fresh")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn commit_replaces_install() {
+        let (_root, temp_dir, target) = setup(true).await;
+        let job = stage_new(&temp_dir, &target).await;
+        job.swap().await.unwrap();
+        job.commit().await.unwrap();
+        assert_eq!(installed_files(&target).await, vec!["new.tbl".to_string()]);
+        no_jobs_left(&temp_dir, &target).await;
+
+        // A committed journal left behind is only cleaned up, not undone.
+        let job = stage_new(&temp_dir, &target).await;
+        job.swap().await.unwrap();
+        let mut journal = job.journal;
+        journal.committed = true;
+        let job = InstallJob {
+            dir: job.dir,
+            journal,
+        };
+        job.write_journal().await.unwrap();
+        drop(job);
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        recover_installs(&temp_dir, &pool).await.unwrap();
+        assert_eq!(installed_files(&target).await, vec!["new.tbl".to_string()]);
+        no_jobs_left(&temp_dir, &target).await;
+    }
+}
@@ -0,0 +1,211 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::fs::{DirBuilder, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use super::readers::ReaderError;
+use super::util::get_cs_path;
+use crate::common::SHA256Checksum;
+
+/// Content-addressed copies of every file we've installed, named by their hash.
+/// Installs link to these instead of holding their own copy,
+/// so a texture shared by a dozen mods only takes up space once.
+#[derive(Debug, Clone)]
+pub struct Store {
+    root: PathBuf,
+}
+
+/// How a stored file ended up at its install location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+impl Store {
+    // Kept in the temp dir beside downloads and staged installs, laid out the same way as downloads.
+    // Links can't cross filesystems, so check `links_to` before using it for an install.
+    pub fn new(temp_dir: impl AsRef<Path>) -> Self {
+        Store {
+            root: temp_dir.as_ref().join("store"),
+        }
+    }
+
+    pub fn path(&self, hash: &SHA256Checksum) -> PathBuf {
+        get_cs_path(&self.root, hash)
+    }
+
+    /// Whether files in the store can be reflinked or hardlinked into `dir`.
+    /// If they'd have to be copied, the store would only be holding another copy of everything.
+    pub async fn links_to(&self, dir: &Path) -> io::Result<bool> {
+        static PROBES: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            ".probe-{}-{}",
+            std::process::id(),
+            PROBES.fetch_add(1, Ordering::Relaxed)
+        );
+        let src = self.root.join(&name);
+        let dest = dir.join(&name);
+        DirBuilder::new().recursive(true).create(&self.root).await?;
+        tokio::fs::write(&src, b"").await?;
+        let linked = tokio::task::spawn_blocking(move || {
+            let linked = reflink_copy::reflink(&src, &dest).is_ok()
+                || std::fs::hard_link(&src, &dest).is_ok();
+            let _ = std::fs::remove_file(&dest);
+            std::fs::remove_file(&src)?;
+            Ok::<_, io::Error>(linked)
+        })
+        .await??;
+        Ok(linked)
+    }
+
+    pub async fn contains(&self, hash: &SHA256Checksum) -> bool {
+        tokio::fs::metadata(self.path(hash)).await.is_ok()
+    }
+
+    /// Save the contents streamed down `rx` as `hash`, returning the stored path and its size.
+    /// Contents that don't match the hash are thrown away rather than stored.
+    pub async fn add(
+        &self,
+        hash: &SHA256Checksum,
+        mut rx: mpsc::Receiver<Result<Bytes, ReaderError>>,
+    ) -> Result<(PathBuf, u64), ReaderError> {
+        let path = self.path(hash);
+        DirBuilder::new()
+            .recursive(true)
+            .create(path.parent().unwrap())
+            .await?;
+        // Written alongside and renamed into place, so a half written file never has a hash's name.
+        // Two installs can store the same file at once, so each gets its own partial file.
+        static PARTIALS: AtomicUsize = AtomicUsize::new(0);
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(
+            ".{}.part",
+            PARTIALS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let partial = PathBuf::from(partial);
+        let mut outfile = File::create(&partial).await?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let written: Result<(), ReaderError> = async {
+            while let Some(chunk) = rx.recv().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                outfile.write_all(&chunk).await?;
+            }
+            outfile.sync_all().await?;
+            Ok(())
+        }
+        .await;
+        drop(outfile);
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        if hasher.finalize().as_slice() != hash.0.as_slice() {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(ReaderError::IOError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("contents don't match {}", hex::encode(&hash.0)),
+            )));
+        }
+        tokio::fs::rename(&partial, &path).await?;
+        Ok((path, size))
+    }
+
+    /// Put a stored file at `dest`, replacing anything already there.
+    /// Reflinks are preferred as writing to them doesn't touch the store,
+    /// a hardlink shares the stored file, and copying is the last resort.
+    /// Hardlinked files are made read-only, as writing to one would change every mod using it.
+    pub async fn place(&self, hash: &SHA256Checksum, dest: &Path) -> io::Result<Placement> {
+        let src = self.path(hash);
+        let dest = dest.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            // Windows won't remove a read-only file, which a hardlink placed earlier is.
+            #[cfg(windows)]
+            if std::fs::metadata(&dest).is_ok_and(|m| m.permissions().readonly()) {
+                set_writable(&dest, true)?;
+            }
+            match std::fs::remove_file(&dest) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            if reflink_copy::reflink(&src, &dest).is_ok() {
+                set_writable(&dest, true)?;
+                return Ok(Placement::Reflink);
+            }
+            if std::fs::hard_link(&src, &dest).is_ok() {
+                // Permissions belong to the file, not the link, so this covers every link to it.
+                set_writable(&src, false)?;
+                Ok(Placement::Hardlink)
+            } else {
+                std::fs::copy(&src, &dest)?;
+                set_writable(&dest, true)?;
+                Ok(Placement::Copy)
+            }
+        })
+        .await?
+    }
+}
+
+// Only touches the owner's write permission on unix, so nothing becomes writable by everyone.
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+    let mut perms = std::fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = perms.mode();
+        perms.set_mode(if writable {
+            mode | 0o200
+        } else {
+            mode & !0o222
+        });
+    }
+    #[cfg(not(unix))]
+    perms.set_readonly(!writable);
+    std::fs::set_permissions(path, perms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn placed_files_never_write_to_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let store = Store::new(root);
+        let contents = Bytes::from_static(b"some mod's texture");
+        let hash = SHA256Checksum(Sha256::digest(&contents).to_vec());
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(Ok(contents.clone())).await.unwrap();
+        drop(tx);
+        let (stored, size) = store.add(&hash, rx).await.unwrap();
+        assert_eq!(stored, get_cs_path(root.join("store"), &hash));
+        assert_eq!(size, contents.len() as u64);
+        assert!(store.contains(&hash).await);
+
+        let dest = root.join("install/data/texture.dds");
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        // Everything's in the system temp dir here, so it's all on one filesystem.
+        assert!(store.links_to(dest.parent().unwrap()).await.unwrap());
+        let placement = store.place(&hash, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), contents);
+        let readonly = std::fs::metadata(&dest).unwrap().permissions().readonly();
+        // Only a hardlink shares the stored file, so only that can't be written to.
+        assert_eq!(readonly, placement == Placement::Hardlink);
+        if placement == Placement::Hardlink {
+            assert!(std::fs::metadata(&stored).unwrap().permissions().readonly());
+        }
+        // Placing it again replaces what's there.
+        store.place(&hash, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), contents);
+    }
+}
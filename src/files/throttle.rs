@@ -0,0 +1,196 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use tokio::sync::RwLock;
+
+use crate::config::Config;
+
+/// Keeps downloads under the bandwidth limits in the config.
+/// Limits are looked up as data arrives, so changes to the config apply to transfers already running.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    config: Arc<RwLock<Config>>,
+    buckets: Arc<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    total: Bucket,
+    lan: Bucket,
+    internet: Bucket,
+}
+
+/// A single download, counted against the total limit and the one for where it's from.
+pub struct Transfer {
+    throttle: Throttle,
+    lan: bool,
+}
+
+impl Throttle {
+    pub fn new(config: Arc<RwLock<Config>>) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    pub fn transfer(&self, url: &str) -> Transfer {
+        Transfer {
+            throttle: self.clone(),
+            lan: is_lan(url),
+        }
+    }
+}
+
+impl Transfer {
+    /// Wait until `bytes` more can be downloaded without going over the limits.
+    pub async fn take(&self, bytes: u64) {
+        let (total_limit, limit) = {
+            let config = self.throttle.config.read().await;
+            let settings = &config.local_settings;
+            let limit = if self.lan {
+                settings.lan_bandwidth_limit
+            } else {
+                settings.internet_bandwidth_limit
+            };
+            (settings.bandwidth_limit, limit)
+        };
+        let buckets = &self.throttle.buckets;
+        let bucket = if self.lan {
+            &buckets.lan
+        } else {
+            &buckets.internet
+        };
+        let wait = bucket
+            .reserve(bytes, limit)
+            .max(buckets.total.reserve(bytes, total_limit));
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// A token bucket holding up to a second's worth of bytes.
+// It's allowed to go into debt, and whoever takes from it next waits for that to be paid off,
+// so transfers sharing it queue up behind each other rather than all bursting at once.
+#[derive(Debug)]
+struct Bucket {
+    // Bytes available, and when that was worked out.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new((f64::INFINITY, Instant::now())),
+        }
+    }
+}
+
+impl Bucket {
+    // Take `bytes` out of the bucket, returning how long to wait before using them.
+    fn reserve(&self, bytes: u64, limit: Option<u64>) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*last).as_secs_f64();
+        *last = now;
+        match limit.filter(|&rate| rate > 0) {
+            Some(rate) => {
+                let rate = rate as f64;
+                // Full while unlimited, so a limit set part way through starts with a burst.
+                *tokens = (*tokens + elapsed * rate).min(rate) - bytes as f64;
+                if *tokens >= 0.0 {
+                    Duration::ZERO
+                } else {
+                    Duration::from_secs_f64(-*tokens / rate)
+                }
+            }
+            None => {
+                *tokens = f64::INFINITY;
+                Duration::ZERO
+            }
+        }
+    }
+}
+
+// Whether a URL points somewhere on the local network, like a gate on another machine at home.
+fn is_lan(url: &str) -> bool {
+    let host = match Url::parse(url) {
+        Ok(parsed) => match parsed.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+    if host == "localhost" || host.ends_with(".local") {
+        return true;
+    }
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            // Unique local (fc00::/7) and link local (fe80::/10) addresses.
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lan_addresses() {
+        for url in [
+            "http://localhost:4000/files/a.vp",
+            "http://gate.local/files/a.vp",
+            "http://192.168.1.5/files/a.vp",
+            "http://10.0.0.2:8080/files/a.vp",
+            "http://172.16.4.1/files/a.vp",
+            "http://127.0.0.1/files/a.vp",
+            "http://169.254.3.3/files/a.vp",
+            "http://[::1]/files/a.vp",
+            "http://[fd12:3456::1]/files/a.vp",
+            "http://[fe80::1]/files/a.vp",
+        ] {
+            assert!(is_lan(url), "{} should be on the LAN", url);
+        }
+        for url in [
+            "https://cf.fsnebula.org/storage/a.7z",
+            "http://8.8.8.8/files/a.vp",
+            "http://172.32.0.1/files/a.vp",
+            "http://[2001:db8::1]/files/a.vp",
+            "not a url",
+        ] {
+            assert!(!is_lan(url), "{} shouldn't be on the LAN", url);
+        }
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let bucket = Bucket::default();
+        assert_eq!(bucket.reserve(u64::MAX, None), Duration::ZERO);
+        // A limit of 0 means no limit.
+        assert_eq!(bucket.reserve(u64::MAX, Some(0)), Duration::ZERO);
+    }
+
+    #[test]
+    fn limited_waits_for_debt() {
+        let bucket = Bucket::default();
+        // Starts full, so up to a second's worth goes straight through.
+        assert_eq!(bucket.reserve(1000, Some(1000)), Duration::ZERO);
+        // Then the next second's worth has to wait for it, give or take the time since.
+        let wait = bucket.reserve(1000, Some(1000));
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        // And whoever's after that queues up behind it.
+        let wait = bucket.reserve(500, Some(1000));
+        assert!(wait > Duration::from_millis(1400) && wait <= Duration::from_millis(1500));
+        // Lifting the limit clears the debt.
+        assert_eq!(bucket.reserve(500, None), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, Some(1000)), Duration::ZERO);
+    }
+}
@@ -19,9 +19,15 @@ pub fn get_cs_path(temp_dir: impl AsRef<Path>, checksum: &SHA256Checksum) -> Pat
 
 pub fn split_hash(checksum: &SHA256Checksum) -> String {
     let data = checksum.0.clone();
-    let cs_filename = format!("{:x}/{:x}/{}", data[0], data[1], encode(&data[2..]));
+    // Padded, as gates expect each directory to be a whole byte.
+    let cs_filename = format!("{:02x}/{:02x}/{}", data[0], data[1], encode(&data[2..]));
     cs_filename
 }
+
+// Config URLs may or may not end in a slash.
+fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path)
+}
 // Fix the error type on this
 pub fn get_urls(
     config: &Config,
@@ -46,7 +52,14 @@ pub fn get_urls(
                     "No FSNebula repositories specified.".to_string(),
                 ))
             } else {
-                let urls = repos.iter().map(|r| r.clone() + &hashpath).collect();
+                // Repos are given by their repo.json, and files are stored alongside it.
+                let urls = repos
+                    .iter()
+                    .map(|r| match r.strip_suffix("repo.json") {
+                        Some(storage) => join_url(storage, &hashpath),
+                        None => join_url(r, &hashpath),
+                    })
+                    .collect();
                 Ok(urls)
             }
         }
@@ -58,7 +71,7 @@ pub fn get_urls(
                     "No sol-gate repositories specified.".to_string(),
                 ))
             } else {
-                let urls = gates.iter().map(|r| r.url.clone() + &hashpath).collect();
+                let urls = gates.iter().map(|r| join_url(&r.url, &hashpath)).collect();
                 Ok(urls)
             }
         }
@@ -68,7 +68,10 @@ impl FSNebula {
                 .send()
                 .await;
             match req_result {
-                Err(_) => continue, // Try next repo.json url
+                Err(e) => {
+                    eprintln!("Couldn't fetch {}, trying the next repo: {}", repo_url, e);
+                    continue; // Try next repo.json url
+                }
                 Ok(response) => match response.status() {
                     StatusCode::OK => {
                         match response.headers().get(ETAG) {
@@ -83,7 +86,10 @@ impl FSNebula {
                         break;
                     }
                     StatusCode::NOT_MODIFIED => break,
-                    _ => continue,
+                    status => {
+                        eprintln!("Fetching {} got {}, trying the next repo", repo_url, status);
+                        continue;
+                    }
                 },
             }
         }
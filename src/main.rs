@@ -2,6 +2,7 @@ use axum::{self};
 use clap::Parser;
 use config::Config;
 use files::readers::ReaderPoolHandle;
+use files::throttle::Throttle;
 use open;
 use reqwest::Client;
 use std::collections::HashMap;
@@ -36,6 +37,7 @@ pub struct SolGateState {
     pub config: Arc<RwLock<config::Config>>,
     pub reader_pool: ReaderPoolHandle,
     pub http_client: Client,
+    pub throttle: Throttle,
 }
 
 #[tokio::main]
@@ -81,16 +83,22 @@ async fn init_state(config: Config) -> Result<SolGateState, Box<dyn std::error::
     let appdir = Config::default_dir();
 
     let sql_pool = db::init(appdir.clone().join("mods.db")).await?;
+    // Don't leave a half installed mod around if we were stopped part way through.
+    if let Err(e) = files::recover_installs(&config.local_settings.temp_dir, &sql_pool).await {
+        eprintln!("Couldn't recover interrupted installs: {}", e);
+    }
 
     let rwl_config = Arc::new(RwLock::new(config));
 
     let reader_pool = ReaderPoolHandle::new(sql_pool.acquire().await?);
     let http_client = Client::new();
+    let throttle = Throttle::new(rwl_config.clone());
     Ok(SolGateState {
         sql_pool,
         config: rwl_config,
         reader_pool,
         http_client,
+        throttle,
     })
 }
 
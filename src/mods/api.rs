@@ -13,8 +13,11 @@ use sqlx::SqlitePool;
 
 use crate::common::{Mod, Package, Release};
 use crate::{
-    db::queries::{self, get_mod_details, get_mod_packages, get_package_files},
-    files::install_files,
+    db::queries::{
+        self, get_hashes_from_ids, get_mod_details, get_mod_packages, get_package_files,
+    },
+    db::DepType,
+    files::{install_files, package_manifest},
     SolGateState,
 };
 
@@ -111,10 +114,37 @@ async fn install_mod(
         .ok_or(ModError::InstallError)?;
     let packages = get_mod_packages(&mod_info.id, &mod_info.version, &mut tx).await?;
     let mut package_details: Vec<(Package, Vec<crate::common::File>)> = Vec::new();
-    for package in packages.into_iter() {
+    // There's no way to pick optional packages yet, so install what the mod recommends.
+    for package in packages
+        .into_iter()
+        .filter(|p| p.status != DepType::Optional)
+    {
         let files = get_package_files(&package.p_id, &mut tx).await?;
         package_details.push((package, files))
     }
-    let parent_dir = mod_details.parent; // None means it's a TC, Some means it's a mod for a TC (or FS2)
-    todo!()
+    let h_ids = package_details
+        .iter()
+        .flat_map(|(_, files)| files.iter().map(|f| f.h_id))
+        .collect();
+    let hashes = get_hashes_from_ids(&h_ids, &mut tx)
+        .await?
+        .into_iter()
+        .map(|h| (h.id, h.val))
+        .collect();
+    tx.commit().await?;
+    let manifest = package_details
+        .iter()
+        .flat_map(|(package, files)| package_manifest(package, files, &hashes))
+        .collect();
+
+    install_files(manifest, mod_details, &sol_state)
+        .await
+        .map_err(|e| {
+            eprintln!(
+                "Installing {} {} failed: {:?}",
+                mod_info.id, mod_info.version, e
+            );
+            ModError::InstallError
+        })?;
+    Ok(())
 }
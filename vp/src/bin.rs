@@ -9,11 +9,17 @@ use clap::{Parser, Subcommand};
 use tokio::{
     self,
     fs::File,
-    io::{AsyncReadExt, AsyncSeekExt, BufReader},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
     task::{JoinHandle},
 };
 
-use vp::{compression::maybe_decompress, fs};
+use vp::{
+    compression::maybe_decompress,
+    fs,
+    path::VPPath,
+    types::{VPDir, VPFile, VPParseError},
+    validate, writer,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -24,10 +30,20 @@ struct Cli {
 
 #[derive(Parser, Debug)]
 struct DCopts {
+    /// VP to extract, or a path inside one such as `mod.vp/data/tables/ships.tbl`
     #[clap(value_parser)]
     input_vp: PathBuf,
     #[clap(value_parser, default_value = ".")]
     output_dir: PathBuf,
+    /// Only extract entries matching this path or glob, i.e. `data/tables/*.tbm`. Can be repeated
+    #[clap(short, long = "entry", value_parser)]
+    entries: Vec<String>,
+    /// Write the contents of matching entries to stdout instead of to files
+    #[clap(short = 'c', long, action)]
+    stdout: bool,
+    /// Put every entry directly in the output directory, dropping the VP's directories
+    #[clap(short = 'j', long, action)]
+    flatten: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -36,20 +52,148 @@ struct Copts {
     input_dir: PathBuf,
     #[clap(value_parser)]
     output_vp: PathBuf,
-    #[clap(short, default_value_t = false)]
+    /// LZ41 compress entries where it saves space
+    #[clap(short, action)]
+    z: bool,
+    /// Give every file this timestamp, in seconds since the Unix epoch,
+    /// instead of its modification time so the VP is reproducible
+    #[clap(short, long, value_parser)]
+    timestamp: Option<u32>,
+}
+
+#[derive(Parser, Debug)]
+struct Lopts {
+    #[clap(value_parser)]
+    input_vp: PathBuf,
+    #[clap(short, long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+    /// Add the SHA-256 of each entry's decompressed contents, as used by sol-gate
+    #[clap(long, action)]
+    hash: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Ropts {
+    #[clap(value_parser)]
+    input_vp: PathBuf,
+    /// Where to write the repacked VP, defaults to replacing the input
+    #[clap(value_parser)]
+    output_vp: Option<PathBuf>,
+    /// Sort the contents of each directory by name
+    #[clap(short, long, action)]
+    sort: bool,
+    /// LZ41 compress entries where it saves space, recompressing any that already are
+    #[clap(short = 'z', long, action, conflicts_with = "decompress")]
+    compress: bool,
+    /// Store every entry uncompressed
+    #[clap(short, long, action)]
+    decompress: bool,
+    /// Only report how much space would be reclaimed
+    #[clap(short = 'n', long, action)]
+    dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Diffopts {
+    #[clap(value_parser)]
+    old_vp: PathBuf,
+    #[clap(value_parser)]
+    new_vp: PathBuf,
+    #[clap(short, long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+}
+
+#[derive(Parser, Debug)]
+struct Mopts {
+    /// VPs and directories to merge, later ones override files in earlier ones
+    #[clap(value_parser, required = true)]
+    inputs: Vec<PathBuf>,
+    #[clap(short, long, value_parser)]
+    output_vp: PathBuf,
+    /// LZ41 compress entries where it saves space
+    #[clap(short, action)]
+    z: bool,
+    /// Give every file this timestamp, in seconds since the Unix epoch,
+    /// instead of keeping the one from its input
+    #[clap(short, long, value_parser)]
+    timestamp: Option<u32>,
+    /// Format of the override report
+    #[clap(short, long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+}
+
+#[derive(Parser, Debug)]
+struct Manopts {
+    #[clap(value_parser)]
+    input_vp: PathBuf,
+    /// Write the directory tree instead of a flat listing
+    #[clap(long, action, conflicts_with = "hash")]
+    tree: bool,
+    /// Add the SHA-256 of each entry's decompressed contents, checked when building
+    #[clap(long, action)]
+    hash: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Bopts {
+    /// JSON tree or listing, as written by the manifest command
+    #[clap(value_parser)]
+    manifest: PathBuf,
+    /// Directory holding the files named in the manifest
+    #[clap(value_parser)]
+    input_dir: PathBuf,
+    #[clap(value_parser)]
+    output_vp: PathBuf,
+    /// LZ41 compress entries where it saves space
+    #[clap(short, action)]
     z: bool,
+    /// Give every file this timestamp, in seconds since the Unix epoch,
+    /// instead of the one in the manifest
+    #[clap(short, long, value_parser)]
+    timestamp: Option<u32>,
+}
+
+#[derive(Parser, Debug)]
+struct Vopts {
+    #[clap(value_parser, required = true)]
+    input_vps: Vec<PathBuf>,
+    #[clap(short, long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Human,
+    Json,
+    Csv,
 }
 
 #[derive(Subcommand, Debug)]
 enum Mode {
     Decompress(DCopts),
     Compress(Copts),
+    /// List the header and entries of a VP
+    #[clap(alias = "info")]
+    List(Lopts),
+    /// Check VPs for corruption, exits with status 1 if any problems are found
+    Verify(Vopts),
+    /// Rewrite a VP without gaps or stale duplicate entries
+    Repack(Ropts),
+    /// Show which files were added, removed, modified or moved between two VPs
+    Diff(Diffopts),
+    /// Combine VPs and directories into one VP, later inputs win on conflicts
+    Merge(Mopts),
+    /// Write the layout of a VP as JSON, to rebuild it later with build
+    Manifest(Manopts),
+    /// Build a VP laid out as a manifest describes, from loose files
+    Build(Bopts),
 }
 
 #[derive(Debug)]
 struct FileContents {
     path: PathBuf,
     contents: Vec<u8>,
+    timestamp: u32,
 }
 
 #[tokio::main]
@@ -61,6 +205,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match cli.mode {
         Mode::Decompress(opts) => decompress(opts).await?,
         Mode::Compress(opts) => compress(opts).await?,
+        Mode::List(opts) => list(opts)?,
+        Mode::Repack(opts) => repack(opts)?,
+        Mode::Diff(opts) => diff(opts)?,
+        Mode::Merge(opts) => merge(opts)?,
+        Mode::Manifest(opts) => manifest(opts)?,
+        Mode::Build(opts) => build(opts)?,
+        Mode::Verify(opts) => {
+            if !verify(opts)? {
+                std::process::exit(1);
+            }
+        }
     }
     Ok(())
 }
@@ -91,18 +246,25 @@ impl<T: std::fmt::Debug> From<async_channel::SendError<T>> for VPReaderError<T>
 }
 
 async fn decompress(opts: DCopts) -> Result<(), Box<dyn std::error::Error>> {
-    let mut index = fs::index(&mut std::fs::File::open(&opts.input_vp)?)?;
-    // Set index of root directory to extraction directory
-    index.name = opts.output_dir.to_string_lossy().to_string();
-    // index.flatten turns it into a Vec of VP files with full paths.
-    let mut files = index.flatten();
+    // Anything after the VP itself in the input path picks out a single entry.
+    let (input_vp, inner_path) = fs::split_path(&opts.input_vp)?;
+    let mut patterns = opts.entries.clone();
+    if !inner_path.is_empty() {
+        patterns.push(inner_path.join("/"));
+    }
+    let index = fs::index(&mut std::fs::File::open(&input_vp)?)?;
+    let mut files = select_entries(&index, &patterns)?;
     files.sort_by_key(|f| f.fileoffset); // Order by file offset so we're not seeking back and forth.
+    if opts.stdout {
+        return extract_stdout(&input_vp, &files);
+    }
+    let files = output_paths(files, &opts.output_dir, opts.flatten)?;
     let (tx_vp, rx_vp) = async_channel::bounded::<FileContents>(32);
     let vp_task: JoinHandle<Result<(), VPReaderError<FileContents>>> =
         tokio::task::spawn(async move {
-            let mut vp = BufReader::new(File::open(opts.input_vp).await?);
+            let mut vp = BufReader::new(File::open(input_vp).await?);
             let mut currpos = 0;
-            for vpfile in files {
+            for (vpfile, out_path) in files {
                 // files in a VP are *usually* contigious, but there's no actual garuantee.
                 // We keep track of the current offset to prevent an unnecessary seek operation
                 // if everything lines up.
@@ -114,11 +276,12 @@ async fn decompress(opts: DCopts) -> Result<(), Box<dyn std::error::Error>> {
                 let mut buf = vec![0u8; vpfile.size.try_into().unwrap()];
                 vp.read_exact(&mut buf).await?;
                 // increment position by how much we've read.
-                currpos += vpfile.size as u64;
+                currpos += vpfile.size;
                 tx_vp
                     .send(FileContents {
-                        path: vpfile.name.into(),
+                        path: out_path,
                         contents: buf,
+                        timestamp: vpfile.timestamp,
                     })
                     .await?;
             }
@@ -134,11 +297,10 @@ async fn decompress(opts: DCopts) -> Result<(), Box<dyn std::error::Error>> {
         decompress_tasks.push( tokio::spawn(
             async move {
             while let Ok(entry) = rx.recv().await {
-                let path = entry.path;
-                let contents = maybe_decompress(entry.contents);
-                tx.send(FileContents { path, contents }).await?
+                let contents = maybe_decompress(entry.contents)?;
+                tx.send(FileContents { contents, ..entry }).await?
             };
-            Result::<(),async_channel::SendError<FileContents>>::Ok(())
+            Result::<(), VPReaderError<FileContents>>::Ok(())
         }))
     }
     drop(tx_uc);
@@ -160,7 +322,15 @@ async fn decompress(opts: DCopts) -> Result<(), Box<dyn std::error::Error>> {
                     .create(dir)
                     .await
                     .unwrap();
-                tokio::fs::write(entry.path, entry.contents).await?
+                let mut file = File::create(entry.path).await?;
+                file.write_all(&entry.contents).await?;
+                file.flush().await?;
+                // 0 means the packer didn't record a time, so leave it as now.
+                if entry.timestamp != 0 {
+                    file.into_std()
+                        .await
+                        .set_modified(fs::timestamp_to_time(entry.timestamp))?;
+                }
             }
             Result::<(),std::io::Error>::Ok(())
         }))
@@ -179,6 +349,656 @@ async fn decompress(opts: DCopts) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Pick out the entries to extract, everything if there are no patterns.
+/// Names in the returned files are relative to the root of the VP.
+fn select_entries(index: &VPDir, patterns: &[String]) -> Result<Vec<VPFile>, Box<dyn Error>> {
+    if patterns.is_empty() {
+        return Ok(index.glob("**"));
+    }
+    let mut files: Vec<VPFile> = Vec::new();
+    for pattern in patterns {
+        let matched = if pattern.contains(['*', '?']) {
+            index.glob(pattern)
+        } else {
+            let path: Vec<String> = pattern
+                .split(['/', '\\'])
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect();
+            let mut file = index
+                .locate(&path)
+                .map_err(|_| format!("{} not found in VP", pattern))?;
+            file.name = path.join("/");
+            vec![file]
+        };
+        if matched.is_empty() {
+            return Err(format!("No entries match {}", pattern).into());
+        }
+        for file in matched {
+            // Overlapping patterns shouldn't extract the same entry twice.
+            if !files.iter().any(|f| f.name == file.name) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Work out where each entry gets written to.
+fn output_paths(
+    files: Vec<VPFile>,
+    output_dir: &Path,
+    flatten: bool,
+) -> Result<Vec<(VPFile, PathBuf)>, Box<dyn Error>> {
+    let mut out: Vec<(VPFile, PathBuf)> = Vec::with_capacity(files.len());
+    for file in files {
+        let vp_path = VPPath::from(&file.name);
+        let path = if flatten {
+            vp_path
+                .split_last()
+                .and_then(|(name, _)| VPPath::from(name).to_fs_path(output_dir))
+        } else {
+            vp_path.to_fs_path(output_dir)
+        };
+        // Names come from the VP, so don't let them point outside output_dir.
+        let path = path.ok_or_else(|| {
+            format!(
+                "Refusing to extract {} outside {}",
+                file.name,
+                output_dir.display()
+            )
+        })?;
+        // Entries with the same name in different directories would overwrite each other.
+        if let Some((other, _)) = out.iter().find(|(_, p)| p == &path) {
+            return Err(format!(
+                "{} and {} would both be written to {}",
+                other.name,
+                file.name,
+                path.display()
+            )
+            .into());
+        }
+        out.push((file, path));
+    }
+    Ok(out)
+}
+
+fn extract_stdout(input_vp: &Path, files: &[VPFile]) -> Result<(), Box<dyn Error>> {
+    use std::io::{Read, Seek, Write};
+    let mut vp = std::fs::File::open(input_vp)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for vpfile in files {
+        vp.seek(SeekFrom::Start(vpfile.fileoffset))?;
+        let mut buf = vec![0u8; vpfile.size.try_into()?];
+        vp.read_exact(&mut buf)?;
+        out.write_all(&maybe_decompress(buf)?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
 async fn compress(opts: Copts) -> Result<(), Box<dyn std::error::Error>> {
-    todo!()
+    let out = File::create(&opts.output_vp).await?;
+    let pack_opts = writer::PackOptions {
+        compress: opts.z,
+        timestamp: opts.timestamp,
+    };
+    writer::async_write_dir(&opts.input_dir, out, &pack_opts).await?;
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Listing {
+    version: u32,
+    index_offset: u32,
+    entries: u32,
+    files: Vec<ListEntry>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ListEntry {
+    path: String,
+    offset: u64,
+    size: u64,
+    timestamp: u32,
+    compressed: bool,
+    uncompressed_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+fn list(opts: Lopts) -> Result<(), Box<dyn std::error::Error>> {
+    use sha2::{Digest, Sha256};
+    use std::io::{Read, Seek, Write};
+    use vp::compression::{LZ4Info, FOOTER_LEN, LZ41_MAGIC};
+
+    let mut vp = std::fs::File::open(&opts.input_vp)?;
+    let head = fs::read_header(&mut vp)?;
+    let index = fs::index(&mut vp)?;
+    let mut files = Vec::new();
+    for vpfile in index.files() {
+        // Only read what we need, unless we're hashing the whole thing.
+        let mut magic = [0u8; 4];
+        if vpfile.size >= (LZ41_MAGIC.len() + FOOTER_LEN) as u64 {
+            vp.seek(SeekFrom::Start(vpfile.fileoffset))?;
+            vp.read_exact(&mut magic)?;
+        }
+        let compressed = &magic == LZ41_MAGIC;
+        let uncompressed_size = if compressed {
+            let mut footer = [0u8; FOOTER_LEN];
+            vp.seek(SeekFrom::Start(
+                vpfile.fileoffset + vpfile.size - FOOTER_LEN as u64,
+            ))?;
+            vp.read_exact(&mut footer)?;
+            LZ4Info::from_entry(&[&magic[..], &footer[..]].concat())?
+                .filesize
+                .into()
+        } else {
+            vpfile.size
+        };
+        let sha256 = if opts.hash {
+            let mut buf = vec![0u8; vpfile.size.try_into()?];
+            vp.seek(SeekFrom::Start(vpfile.fileoffset))?;
+            vp.read_exact(&mut buf)?;
+            let digest = Sha256::digest(maybe_decompress(buf)?);
+            Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+        } else {
+            None
+        };
+        files.push(ListEntry {
+            path: vpfile.name,
+            offset: vpfile.fileoffset,
+            size: vpfile.size,
+            timestamp: vpfile.timestamp,
+            compressed,
+            uncompressed_size,
+            sha256,
+        });
+    }
+    let listing = Listing {
+        version: head.version,
+        index_offset: head.offset,
+        entries: head.entries,
+        files,
+    };
+
+    let mut out = std::io::stdout().lock();
+    match opts.format {
+        Format::Json => writeln!(out, "{}", serde_json::to_string_pretty(&listing)?)?,
+        Format::Csv => {
+            let mut header = "path,offset,size,timestamp,compressed,uncompressed_size".to_string();
+            if opts.hash {
+                header.push_str(",sha256");
+            }
+            writeln!(out, "{}", header)?;
+            for f in listing.files.iter() {
+                let mut line = format!(
+                    "{},{},{},{},{},{}",
+                    csv_field(&f.path),
+                    f.offset,
+                    f.size,
+                    f.timestamp,
+                    f.compressed,
+                    f.uncompressed_size
+                );
+                if let Some(hash) = &f.sha256 {
+                    line.push(',');
+                    line.push_str(hash);
+                }
+                writeln!(out, "{}", line)?;
+            }
+        }
+        Format::Human => {
+            writeln!(out, "{}", opts.input_vp.display())?;
+            writeln!(
+                out,
+                "version {}, {} index entries at offset {}, {} files",
+                listing.version,
+                listing.entries,
+                listing.index_offset,
+                listing.files.len()
+            )?;
+            for f in listing.files.iter() {
+                let size = if f.compressed {
+                    format!("{} ({} lz41)", f.uncompressed_size, f.size)
+                } else {
+                    f.size.to_string()
+                };
+                write!(
+                    out,
+                    "{:>10} {:>24} {:>10}  {}",
+                    f.offset, size, f.timestamp, f.path
+                )?;
+                match &f.sha256 {
+                    Some(hash) => writeln!(out, "  {}", hash)?,
+                    None => writeln!(out)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn repack(opts: Ropts) -> Result<(), Box<dyn std::error::Error>> {
+    let repack_opts = writer::RepackOptions {
+        sort: opts.sort,
+        compress: match (opts.compress, opts.decompress) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+    };
+    let mut src = std::io::BufReader::new(std::fs::File::open(&opts.input_vp)?);
+    let stats = if opts.dry_run {
+        writer::repack(&mut src, SizeCounter::default(), &repack_opts)?.1
+    } else {
+        // Write next to the destination then move it into place,
+        // so a failure never leaves a half written VP behind.
+        let output_vp = opts.output_vp.as_ref().unwrap_or(&opts.input_vp);
+        let mut tmp_name = output_vp.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let out = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        match writer::repack(&mut src, out, &repack_opts) {
+            Ok((out, stats)) => {
+                // Close both first, Windows won't rename an open file,
+                // and with no output given the input is the file being replaced.
+                drop(out);
+                drop(src);
+                std::fs::rename(&tmp_path, output_vp)?;
+                stats
+            }
+            Err(e) => {
+                std::fs::remove_file(&tmp_path)?;
+                return Err(e.into());
+            }
+        }
+    };
+
+    for duplicate in stats.duplicates.iter() {
+        println!("Dropped stale duplicate {}", duplicate);
+    }
+    let verb = if opts.dry_run {
+        "Would reclaim"
+    } else {
+        "Reclaimed"
+    };
+    println!(
+        "{} {} bytes, {} -> {} bytes with {} files",
+        verb,
+        stats.reclaimed(),
+        stats.old_size,
+        stats.new_size,
+        stats.files
+    );
+    Ok(())
+}
+
+/// Discards everything written to it, keeping track of how big the file would have been.
+#[derive(Default)]
+struct SizeCounter {
+    pos: u64,
+    len: u64,
+}
+
+impl std::io::Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::io::Seek for SizeCounter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MergeOverride {
+    path: String,
+    overridden: String,
+    by: String,
+}
+
+fn manifest(opts: Manopts) -> Result<(), Box<dyn std::error::Error>> {
+    use vp::manifest::{hashed_listing, listing, Layout};
+
+    let mut vp = std::io::BufReader::new(std::fs::File::open(&opts.input_vp)?);
+    let layout = if opts.tree {
+        Layout::Tree(fs::index(&mut vp)?)
+    } else if opts.hash {
+        Layout::Listing(hashed_listing(&mut vp)?)
+    } else {
+        Layout::Listing(listing(&fs::index(&mut vp)?))
+    };
+    println!("{}", serde_json::to_string_pretty(&layout)?);
+    Ok(())
+}
+
+fn build(opts: Bopts) -> Result<(), Box<dyn std::error::Error>> {
+    use vp::manifest::{dir_contents, write_listing, Layout};
+
+    let manifest = std::fs::read_to_string(&opts.manifest)?;
+    let entries = serde_json::from_str::<Layout>(&manifest)?.into_listing();
+    let pack_opts = writer::PackOptions {
+        compress: opts.z,
+        timestamp: opts.timestamp,
+    };
+    let out = std::io::BufWriter::new(std::fs::File::create(&opts.output_vp)?);
+    let res = write_listing(&entries, out, &pack_opts, dir_contents(&opts.input_dir));
+    if let Err(e) = res {
+        // Don't leave half a VP lying around.
+        std::fs::remove_file(&opts.output_vp)?;
+        return Err(format!("{}: {}", opts.output_vp.display(), e).into());
+    }
+    Ok(())
+}
+
+fn merge(opts: Mopts) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use vp::merge::MergeInput;
+
+    let inputs: Vec<MergeInput> = opts.inputs.iter().map(MergeInput::from_path).collect();
+    let pack_opts = writer::PackOptions {
+        compress: opts.z,
+        timestamp: opts.timestamp,
+    };
+    // The output could well be one of the inputs, so don't overwrite it until we're done.
+    let mut tmp_name = opts.output_vp.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let out = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    let report = match vp::merge::merge(&inputs, out, &pack_opts) {
+        Ok((out, report)) => {
+            // Close it first, Windows won't rename an open file.
+            drop(out);
+            std::fs::rename(&tmp_path, &opts.output_vp)?;
+            report
+        }
+        Err(e) => {
+            std::fs::remove_file(&tmp_path)?;
+            return Err(e.into());
+        }
+    };
+
+    let input_name = |i: usize| opts.inputs[i].to_string_lossy().to_string();
+    let overrides: Vec<MergeOverride> = report
+        .overrides
+        .iter()
+        .map(|o| MergeOverride {
+            path: o.path.clone(),
+            overridden: input_name(o.old),
+            by: input_name(o.new),
+        })
+        .collect();
+    let mut out = std::io::stdout().lock();
+    match opts.format {
+        Format::Json => writeln!(out, "{}", serde_json::to_string_pretty(&overrides)?)?,
+        Format::Csv => {
+            writeln!(out, "path,overridden,by")?;
+            for o in overrides.iter() {
+                writeln!(
+                    out,
+                    "{},{},{}",
+                    csv_field(&o.path),
+                    csv_field(&o.overridden),
+                    csv_field(&o.by)
+                )?;
+            }
+        }
+        Format::Human => {
+            for o in overrides.iter() {
+                writeln!(out, "{}: {} overrides {}", o.path, o.by, o.overridden)?;
+            }
+            writeln!(
+                out,
+                "Wrote {} files to {}, {} overridden",
+                report.files,
+                opts.output_vp.display(),
+                overrides.len()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct DiffSummary {
+    added: Vec<DiffEntry>,
+    removed: Vec<DiffEntry>,
+    modified: Vec<DiffModified>,
+    moved: Vec<DiffMoved>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DiffEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DiffModified {
+    path: String,
+    old_size: u64,
+    new_size: u64,
+    old_sha256: String,
+    new_sha256: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DiffMoved {
+    from: String,
+    to: String,
+    size: u64,
+    sha256: String,
+}
+
+fn diff(opts: Diffopts) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use vp::diff::Change;
+
+    let mut old = std::io::BufReader::new(std::fs::File::open(&opts.old_vp)?);
+    let mut new = std::io::BufReader::new(std::fs::File::open(&opts.new_vp)?);
+    let changes = vp::diff::diff(&mut old, &mut new)?;
+
+    let mut out = std::io::stdout().lock();
+    match opts.format {
+        Format::Json => {
+            let mut summary = DiffSummary::default();
+            for change in changes {
+                match change {
+                    Change::Added(e) => summary.added.push(DiffEntry {
+                        sha256: e.hex_hash(),
+                        path: e.path,
+                        size: e.size,
+                    }),
+                    Change::Removed(e) => summary.removed.push(DiffEntry {
+                        sha256: e.hex_hash(),
+                        path: e.path,
+                        size: e.size,
+                    }),
+                    Change::Modified { old, new } => summary.modified.push(DiffModified {
+                        old_sha256: old.hex_hash(),
+                        new_sha256: new.hex_hash(),
+                        path: new.path,
+                        old_size: old.size,
+                        new_size: new.size,
+                    }),
+                    Change::Moved { old, new } => summary.moved.push(DiffMoved {
+                        sha256: new.hex_hash(),
+                        from: old.path,
+                        to: new.path,
+                        size: new.size,
+                    }),
+                }
+            }
+            writeln!(out, "{}", serde_json::to_string_pretty(&summary)?)?
+        }
+        Format::Csv => {
+            writeln!(out, "change,path,old_path,old_size,new_size")?;
+            for change in changes.iter() {
+                let (kind, old_path, old_size, new_size) = match change {
+                    Change::Added(e) => ("added", "", None, Some(e.size)),
+                    Change::Removed(e) => ("removed", "", Some(e.size), None),
+                    Change::Modified { old, new } => {
+                        ("modified", "", Some(old.size), Some(new.size))
+                    }
+                    Change::Moved { old, new } => {
+                        ("moved", old.path.as_str(), Some(old.size), Some(new.size))
+                    }
+                };
+                let size = |s: Option<u64>| s.map(|s| s.to_string()).unwrap_or_default();
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    kind,
+                    csv_field(change.path()),
+                    csv_field(old_path),
+                    size(old_size),
+                    size(new_size)
+                )?;
+            }
+        }
+        Format::Human => {
+            let mut counts = [0usize; 4];
+            for change in changes.iter() {
+                match change {
+                    Change::Added(e) => {
+                        counts[0] += 1;
+                        writeln!(out, "A {} ({} bytes)", e.path, e.size)?
+                    }
+                    Change::Removed(e) => {
+                        counts[1] += 1;
+                        writeln!(out, "D {} ({} bytes)", e.path, e.size)?
+                    }
+                    Change::Modified { old, new } => {
+                        counts[2] += 1;
+                        writeln!(out, "M {} ({} -> {} bytes)", new.path, old.size, new.size)?
+                    }
+                    Change::Moved { old, new } => {
+                        counts[3] += 1;
+                        writeln!(out, "R {} -> {} ({} bytes)", old.path, new.path, new.size)?
+                    }
+                }
+            }
+            writeln!(
+                out,
+                "{} added, {} removed, {} modified, {} moved",
+                counts[0], counts[1], counts[2], counts[3]
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Verification {
+    path: String,
+    ok: bool,
+    /// Set if the VP couldn't be read at all.
+    error: Option<String>,
+    problems: Vec<VerifyProblem>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct VerifyProblem {
+    kind: &'static str,
+    entry: Option<String>,
+    message: String,
+}
+
+// Returns whether every VP passed.
+fn verify(opts: Vopts) -> Result<bool, Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let mut results = Vec::new();
+    for input_vp in opts.input_vps.iter() {
+        let checked = std::fs::File::open(input_vp)
+            .map_err(VPParseError::from)
+            .and_then(|mut vp| validate::validate(&mut vp));
+        let (error, problems) = match checked {
+            Ok(problems) => (None, problems),
+            Err(e) => (Some(e.to_string()), Vec::new()),
+        };
+        results.push(Verification {
+            path: input_vp.to_string_lossy().to_string(),
+            ok: error.is_none() && problems.is_empty(),
+            error,
+            problems: problems
+                .iter()
+                .map(|p| VerifyProblem {
+                    kind: p.kind(),
+                    entry: p.path().map(String::from),
+                    message: p.to_string(),
+                })
+                .collect(),
+        });
+    }
+
+    let mut out = std::io::stdout().lock();
+    match opts.format {
+        Format::Json => writeln!(out, "{}", serde_json::to_string_pretty(&results)?)?,
+        Format::Csv => {
+            writeln!(out, "vp,kind,entry,message")?;
+            for r in results.iter() {
+                if let Some(error) = &r.error {
+                    writeln!(
+                        out,
+                        "{},unreadable,,{}",
+                        csv_field(&r.path),
+                        csv_field(error)
+                    )?;
+                }
+                for p in r.problems.iter() {
+                    writeln!(
+                        out,
+                        "{},{},{},{}",
+                        csv_field(&r.path),
+                        p.kind,
+                        csv_field(p.entry.as_deref().unwrap_or_default()),
+                        csv_field(&p.message)
+                    )?;
+                }
+            }
+        }
+        Format::Human => {
+            for r in results.iter() {
+                match &r.error {
+                    Some(error) => writeln!(out, "{}: unreadable, {}", r.path, error)?,
+                    None if r.ok => writeln!(out, "{}: OK", r.path)?,
+                    None => {
+                        writeln!(out, "{}: {} problems", r.path, r.problems.len())?;
+                        for p in r.problems.iter() {
+                            writeln!(out, "  {}", p.message)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(results.iter().all(|r| r.ok))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
 }
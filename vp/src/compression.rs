@@ -1,8 +1,26 @@
+use std::io;
+
 use nom::number::complete::le_u32;
 use nom::IResult;
 
 use lz4::block::{compress, decompress};
 
+/// Compressed VP entries start with this, followed by LZ4 blocks,
+/// a table of block offsets and finally an `LZ4Info` footer.
+pub const LZ41_MAGIC: &[u8; 4] = b"LZ41";
+pub const FOOTER_LEN: usize = 12;
+/// FSO decompresses in blocks of this size, matching LZ41_BLOCK_BYTES.
+pub const BLOCK_SIZE: u32 = 65536;
+/// Largest block size we'll decompress. FSO only ever writes `BLOCK_SIZE`,
+/// this just stops a corrupt footer asking for a huge allocation.
+pub const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+// LZ4 can't expand data by more than this, so anything claiming to is corrupt.
+const MAX_RATIO: usize = 255;
+
+/// FSO streams these straight from the VP rather than going through cfile's
+/// decompression, so they have to be stored uncompressed.
+pub const UNCOMPRESSED_EXTENSIONS: [&str; 6] = ["ogg", "wav", "mve", "ogv", "fs2", "fc2"];
+
 pub struct LZ4Info {
     pub offsets: u32,
     pub filesize: u32,
@@ -23,33 +41,400 @@ impl LZ4Info {
             },
         ))
     }
+
+    /// Read the footer from the end of a compressed entry.
+    pub fn from_entry(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < LZ41_MAGIC.len() + FOOTER_LEN {
+            return Err(invalid("LZ41 entry too short for footer"));
+        }
+        let (_, info) = Self::parse(&buf[buf.len() - FOOTER_LEN..])
+            .map_err(|_| invalid("could not parse LZ41 footer"))?;
+        Ok(info)
+    }
+
+    /// Number of LZ4 blocks the file should have been split into.
+    pub fn blocks(&self) -> u32 {
+        if self.blocksize == 0 {
+            0
+        } else {
+            (self.filesize as u64).div_ceil(self.blocksize as u64) as u32
+        }
+    }
+
+    /// Decompressed size of a given block, only the last one can be short.
+    pub fn block_len(&self, block: u32) -> usize {
+        let start = block as usize * self.blocksize as usize;
+        std::cmp::min(self.blocksize as usize, self.filesize as usize - start)
+    }
+
+    /// Size of the block offset table that sits before the footer.
+    pub fn table_len(&self) -> usize {
+        self.offsets as usize * 4
+    }
+
+    /// Read and sanity check the block offset table of a compressed entry.
+    /// Offsets are from the start of the entry (including the magic),
+    /// and there is one more offset than there are blocks, marking where the last block ends.
+    pub fn block_offsets(&self, buf: &[u8]) -> io::Result<Vec<usize>> {
+        let table_len = self.table_len();
+        if buf.len() < LZ41_MAGIC.len() + FOOTER_LEN + table_len {
+            return Err(invalid("LZ41 offset table doesn't fit in entry"));
+        }
+        let table_start = buf.len() - FOOTER_LEN - table_len;
+        self.parse_offsets(&buf[table_start..buf.len() - FOOTER_LEN], buf.len())
+    }
+
+    /// As `block_offsets`, but for when the offset table has been read separately
+    /// from an entry `entry_len` bytes long, i.e. when streaming.
+    pub fn parse_offsets(&self, table: &[u8], entry_len: usize) -> io::Result<Vec<usize>> {
+        let table_len = self.table_len();
+        if self.offsets == 0
+            || table.len() != table_len
+            || entry_len < LZ41_MAGIC.len() + FOOTER_LEN + table_len
+        {
+            return Err(invalid("LZ41 offset table doesn't fit in entry"));
+        }
+        // The footer sizes allocations, so don't believe it if it's impossibly large.
+        if self.blocksize > MAX_BLOCK_SIZE
+            || self.filesize as usize > entry_len.saturating_mul(MAX_RATIO)
+        {
+            return Err(invalid("LZ41 sizes too large for entry"));
+        }
+        if self.blocksize == 0 || self.offsets - 1 != self.blocks() {
+            return Err(invalid("LZ41 block count doesn't match file size"));
+        }
+        let table_start = entry_len - FOOTER_LEN - table_len;
+        let offsets: Vec<usize> = table
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+            .collect();
+        let in_bounds = offsets[0] >= LZ41_MAGIC.len() && offsets[offsets.len() - 1] <= table_start;
+        if !in_bounds || offsets.windows(2).any(|w| w[0] > w[1]) {
+            return Err(invalid("LZ41 block offsets out of order or out of bounds"));
+        }
+        Ok(offsets)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn is_compressed(buf: &[u8]) -> bool {
+    buf.starts_with(LZ41_MAGIC)
 }
 
 // Pass Vec<u8> in so we can either consume it or return it.
-pub fn maybe_decompress(buf: Vec<u8>) -> Vec<u8> {
-    if &buf[..4] == "LZ41".as_bytes() {
-        real_decompress(&buf[4..])
+pub fn maybe_decompress(buf: Vec<u8>) -> io::Result<Vec<u8>> {
+    if is_compressed(&buf) {
+        real_decompress(&buf)
     } else {
-        buf
+        Ok(buf)
+    }
+}
+
+/// Decompress a single LZ4 block.
+/// Each block is compressed independently, so FSO can seek inside compressed files.
+pub fn decompress_block(block: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    if len > MAX_BLOCK_SIZE as usize || len > block.len().saturating_mul(MAX_RATIO) {
+        return Err(invalid("LZ41 block too large"));
+    }
+    let out = decompress(
+        block,
+        Some(len.try_into().map_err(|_| invalid("block too large"))?),
+    )?;
+    if out.len() != len {
+        return Err(invalid("LZ41 block decompressed to wrong size"));
     }
+    Ok(out)
 }
 
-fn real_decompress(buf: &[u8]) -> Vec<u8> {
-    // Untested lol
-    todo!("decompression not implemented");
-    let end: usize = buf.len() - 12;
-    let info = LZ4Info::parse(&buf[end..]).unwrap().1;
-    let len = info.filesize.try_into().unwrap();
-    decompress(buf, Some(len)).unwrap()
+fn real_decompress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let info = LZ4Info::from_entry(buf)?;
+    let offsets = info.block_offsets(buf)?;
+    let mut out = Vec::with_capacity(info.filesize as usize);
+    for (block, bounds) in offsets.windows(2).enumerate() {
+        let len = info.block_len(block as u32);
+        out.extend(decompress_block(&buf[bounds[0]..bounds[1]], len)?);
+    }
+    Ok(out)
 }
 
-pub fn maybe_compress(buf: Vec<u8>) -> Vec<u8> {
-    let _raw_compressed = compress(&buf, None, false).unwrap();
-    // Add extra stuff to make full compressed vec.
-    // if compressed.len() < buf.len() {
-    //     compressed;
-    // } else {
-    //     buf;
-    // }
-    buf // TODO
+/// Check if FSO will read a given path from a compressed entry.
+pub fn compressible(path: &str) -> bool {
+    match path.rsplit_once('.') {
+        Some((_, ext)) => !UNCOMPRESSED_EXTENSIONS
+            .iter()
+            .any(|u| u.eq_ignore_ascii_case(ext)),
+        None => true,
+    }
+}
+
+// Pass Vec<u8> in so we can either consume it or return it.
+// Only returns compressed data if it's both allowed and actually smaller.
+pub fn maybe_compress(path: &str, buf: Vec<u8>) -> Vec<u8> {
+    if !compressible(path) || is_compressed(&buf) || buf.len() > u32::MAX as usize {
+        return buf;
+    }
+    match real_compress(&buf) {
+        Ok(compressed) if compressed.len() < buf.len() => compressed,
+        _ => buf,
+    }
+}
+
+fn real_compress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let too_big = |_| io::Error::new(io::ErrorKind::InvalidInput, "entry too large to compress");
+    let mut out = LZ41_MAGIC.to_vec();
+    let mut offsets: Vec<u32> = vec![LZ41_MAGIC.len() as u32];
+    for block in buf.chunks(BLOCK_SIZE as usize) {
+        out.extend(compress(block, None, false)?);
+        offsets.push(out.len().try_into().map_err(too_big)?);
+    }
+    for offset in offsets.iter() {
+        out.extend(offset.to_le_bytes());
+    }
+    let offset_count: u32 = offsets.len().try_into().map_err(too_big)?;
+    let filesize: u32 = buf.len().try_into().map_err(too_big)?;
+    out.extend(offset_count.to_le_bytes());
+    out.extend(filesize.to_le_bytes());
+    out.extend(BLOCK_SIZE.to_le_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lay out an entry the same way FSO's lz41_compress_memory does.
+    fn lz41(raw: &[u8], blocksize: usize) -> Vec<u8> {
+        let mut out = LZ41_MAGIC.to_vec();
+        let mut offsets = vec![out.len() as u32];
+        for chunk in raw.chunks(blocksize) {
+            out.extend(compress(chunk, None, false).unwrap());
+            offsets.push(out.len() as u32);
+        }
+        for offset in offsets.iter() {
+            out.extend(offset.to_le_bytes());
+        }
+        out.extend((offsets.len() as u32).to_le_bytes());
+        out.extend((raw.len() as u32).to_le_bytes());
+        out.extend((blocksize as u32).to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn decompress_blocks() {
+        let raw = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        // radar-asteroid.dds is 1520 bytes, so we get a short block at the end.
+        let compressed = lz41(&raw, 512);
+        assert_eq!(maybe_decompress(compressed).unwrap(), raw);
+        let compressed = lz41(&raw, 65536);
+        assert_eq!(maybe_decompress(compressed).unwrap(), raw);
+    }
+
+    // Made independently of this crate's compressor, see credits.md.
+    #[test]
+    fn decompress_sample_vpc() {
+        use crate::reader::VPArchive;
+        use sha2::{Digest, Sha256};
+
+        let archive = VPArchive::open("./test_files/lz41_samples.vpc").unwrap();
+        let dds = archive.entry("data/hud/radar-asteroid.dds").unwrap();
+        let raw = archive.read_raw(dds).unwrap().to_vec();
+        let info = LZ4Info::from_entry(&raw).unwrap();
+        assert_eq!(
+            (info.offsets, info.filesize, info.blocksize),
+            (2, 1520, 65536)
+        );
+        let expected = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        assert_eq!(maybe_decompress(raw).unwrap(), expected);
+
+        // Seven blocks, the last one short.
+        let tbl = archive.entry("data/tables/ships.tbl").unwrap();
+        let raw = archive.read_raw(tbl).unwrap().to_vec();
+        let info = LZ4Info::from_entry(&raw).unwrap();
+        assert_eq!(
+            (info.offsets, info.filesize, info.blocksize),
+            (8, 434909, 65536)
+        );
+        let contents = maybe_decompress(raw).unwrap();
+        assert!(contents.starts_with(b"#Ship Classes\n$Name: GTF Ulysses#0\n"));
+        assert_eq!(
+            format!("{:x}", Sha256::digest(&contents)),
+            "252309eccecfd236e65bfdbc555582c358266fa860eee1958dc466b01c375dd8"
+        );
+    }
+
+    // mv_radaricons.vp is as shipped in the mediaVPs, and FSO reads its entries through the same path.
+    #[test]
+    fn decode_shipped_vp() {
+        use crate::reader::VPArchive;
+        use sha2::{Digest, Sha256};
+
+        let expected = [
+            (
+                "data/hud/radar-asteroid.dds",
+                "6ae766ca5f822b7661e0a7a191f333881d37585bc0aabf45af2eb6d0f99739cc",
+            ),
+            (
+                "data/hud/radar-bigship.dds",
+                "7bb4a00f17380b73aa41bb4bd0bd5efce4ba98ace39c9b9ead7919920443a0f8",
+            ),
+            (
+                "data/hud/radar-bomber.dds",
+                "37cf2fc178d8378a737d3cf53e9475b29d1340297e887a9686d2d1310de3f9e7",
+            ),
+            (
+                "data/hud/radar-capital.dds",
+                "109ce3e7e984b1fceda100d9cbbf6499c4a91acc23a21eea5850c4316af24304",
+            ),
+            (
+                "data/hud/radar-cargo.dds",
+                "7232f9ce93c72bf3eee31380ffce1bb1f5c1dea4f403605deba5cb2f556a088c",
+            ),
+            (
+                "data/hud/radar-cargo2.dds",
+                "6c3c03a1a03297b72637f15a11e47349bfaf37c77aca76357add461f49d51a99",
+            ),
+            (
+                "data/hud/radar-colossus.dds",
+                "4798dbe696a2a79aae9793340c5452f01f0e7636688ed157ae355edd9c715a03",
+            ),
+            (
+                "data/hud/radar-corvette.dds",
+                "239f1912d692f3bda387ea35ba1648ca1860b1a01fdc0d7efb5e710cbe995324",
+            ),
+            (
+                "data/hud/radar-cruiser.dds",
+                "35705617670363842d74de5dac98616759579342d52a29903b6705fb94b7bf5e",
+            ),
+            (
+                "data/hud/radar-drydock.dds",
+                "60f8849992bce965665e70b327571307292a0b3536ee934a2eb2f5dc46ed6731",
+            ),
+            (
+                "data/hud/radar-fighter.dds",
+                "ef97f560d466abe41a79e9c0006061d148247a5f03d8632eb2a6407196272c3e",
+            ),
+            (
+                "data/hud/radar-freighter.dds",
+                "6bc36ae8879f917eb0f9ab7bf6b0ec45bc15304e3c1edaf8bf71f19d49edd6ac",
+            ),
+            (
+                "data/hud/radar-hades.dds",
+                "5e1d6b9bc37804e7549171c35f83b473a235d50575a5b9beef0799fa705152e6",
+            ),
+            (
+                "data/hud/radar-installation.dds",
+                "86c48cb31e55b5b5cdc196e80a70adf181e760af5be566e0910ceed718d434b3",
+            ),
+            (
+                "data/hud/radar-knossos.dds",
+                "8a5718a2eb2f6726e1376b75f647a0bee3c9fd5ed2db70d1e930938ae91245ee",
+            ),
+            (
+                "data/hud/radar-lucifer.dds",
+                "a4c325d2b767fa47dc88dba1045a33fcda130d2e828f0c8f7c8c9e5b16fcefc6",
+            ),
+            (
+                "data/hud/radar-sathanas.dds",
+                "dce17830021208a8c3d752418b61bf55e502a4b2a7e1b6f5c82a513aeaa12642",
+            ),
+            (
+                "data/hud/radar-science.dds",
+                "2ec5967359b15a62222ba6faf10acb5ac8b567df82a6c186411948a37946f992",
+            ),
+            (
+                "data/hud/radar-sentry.dds",
+                "61830108ab3d14ae69f6f9ecb82db57a9e78657d721041fb3f513f37954f1ac6",
+            ),
+            (
+                "data/hud/radar-smallship.dds",
+                "ed804273d6da37625178bb7cb709e35aca3d39944f7e7785289518ab713e327e",
+            ),
+            (
+                "data/hud/radar-unknownbig.dds",
+                "54b1e665be289a2e013ab84424bc4f7c4d963145e3f1963e91efc29add678c76",
+            ),
+            (
+                "data/hud/radar-unknownsmall.dds",
+                "7e90b16feba8e3daa6e77315fa9b0052cf8a183efe97ed447d18e114cd5bae8e",
+            ),
+            (
+                "data/hud/radar-waypoint.dds",
+                "0a9364a8b87fe3a9301d47ce9a93d4f566561361b28ed0aab305ab2993229d00",
+            ),
+            (
+                "data/tables/radar-shp.tbm",
+                "f3cb3e6283ebe7163c8275b1e97c79256528b105b2d2b674f1aeceed03149928",
+            ),
+        ];
+        let archive = VPArchive::open("./test_files/mv_radaricons.vp").unwrap();
+        assert_eq!(archive.index().flatten().len(), expected.len());
+        for (path, hash) in expected {
+            let entry = archive.entry(path).unwrap();
+            let contents = maybe_decompress(archive.read_raw(entry).unwrap().to_vec()).unwrap();
+            assert_eq!(format!("{:x}", Sha256::digest(&contents)), hash, "{}", path);
+        }
+        // The sample .vpc's copy of radar-asteroid.dds decompresses to the shipped one.
+        let vpc = VPArchive::open("./test_files/lz41_samples.vpc").unwrap();
+        let dds = vpc.entry("data/hud/radar-asteroid.dds").unwrap();
+        let contents = maybe_decompress(vpc.read_raw(dds).unwrap().to_vec()).unwrap();
+        assert_eq!(format!("{:x}", Sha256::digest(&contents)), expected[0].1);
+    }
+
+    #[test]
+    fn passthrough_uncompressed() {
+        let raw = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        assert_eq!(maybe_decompress(raw.clone()).unwrap(), raw);
+        assert_eq!(maybe_decompress(vec![]).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let raw = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        let compressed = maybe_compress("radar-asteroid.dds", raw.clone());
+        assert!(is_compressed(&compressed));
+        assert!(compressed.len() < raw.len());
+        assert_eq!(maybe_decompress(compressed).unwrap(), raw);
+        // Multiple blocks, with a short one at the end.
+        let tbl = "$Name: GTF Ulysses\n".repeat(10000).into_bytes();
+        let compressed = maybe_compress("data/tables/ships.tbl", tbl.clone());
+        let info = LZ4Info::from_entry(&compressed).unwrap();
+        assert_eq!(info.blocks(), 3);
+        assert_eq!(maybe_decompress(compressed).unwrap(), tbl);
+    }
+
+    #[test]
+    fn skip_compression() {
+        let raw = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        assert_eq!(maybe_compress("data/music/ambient.OGG", raw.clone()), raw);
+        // Not worth compressing.
+        let tiny = b"#End".to_vec();
+        assert_eq!(maybe_compress("data/tables/tiny.tbm", tiny.clone()), tiny);
+        assert_eq!(maybe_compress("empty.txt", vec![]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn detect_bad_footer() {
+        let raw = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        let compressed = lz41(&raw, 512);
+        // Chopped off footer
+        assert!(maybe_decompress(compressed[..compressed.len() - 4].to_vec()).is_err());
+        // Wrong file size
+        let mut wrong_size = compressed.clone();
+        let len = wrong_size.len();
+        wrong_size[len - 8..len - 4].copy_from_slice(&4000u32.to_le_bytes());
+        assert!(maybe_decompress(wrong_size).is_err());
+        // Just the magic
+        assert!(maybe_decompress(LZ41_MAGIC.to_vec()).is_err());
+        // Sizes too large to have come from this entry, which would be allocated up front.
+        let mut huge_file = compressed.clone();
+        huge_file[len - 8..len - 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(maybe_decompress(huge_file).is_err());
+        let mut huge_block = compressed.clone();
+        huge_block[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(maybe_decompress(huge_block).is_err());
+        assert!(decompress_block(&compressed[4..20], 1 << 30).is_err());
+    }
 }
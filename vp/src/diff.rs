@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use sha2::{Digest, Sha256};
+
+use crate::compression::maybe_decompress;
+use crate::fs;
+use crate::path::VPPath;
+use crate::types::VPParseError;
+
+/// A file in a VP, identified by the SHA-256 of its decompressed contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashedEntry {
+    pub path: String,
+    /// Decompressed size, so compressing a VP doesn't change anything.
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+impl HashedEntry {
+    pub fn hex_hash(&self) -> String {
+        self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added(HashedEntry),
+    Removed(HashedEntry),
+    Modified {
+        old: HashedEntry,
+        new: HashedEntry,
+    },
+    /// Same contents, different path.
+    Moved {
+        old: HashedEntry,
+        new: HashedEntry,
+    },
+}
+
+impl Change {
+    /// Path the change is listed under, the new one for moves.
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(e) | Change::Removed(e) => &e.path,
+            Change::Modified { new, .. } | Change::Moved { new, .. } => &new.path,
+        }
+    }
+}
+
+/// Hash every file in a VP, in index order.
+pub fn hash_entries<T: Read + Seek>(handle: &mut T) -> Result<Vec<HashedEntry>, VPParseError> {
+    let index = fs::index(handle)?;
+    let mut entries = Vec::new();
+    for file in index.files() {
+        handle.seek(SeekFrom::Start(file.fileoffset))?;
+        let mut buf = vec![0u8; file.size as usize];
+        handle.read_exact(&mut buf)?;
+        let contents = maybe_decompress(buf)?;
+        entries.push(HashedEntry {
+            path: file.name,
+            size: contents.len() as u64,
+            sha256: Sha256::digest(&contents).into(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Compare two VPs by the contents of their files.
+pub fn diff<A: Read + Seek, B: Read + Seek>(
+    old: &mut A,
+    new: &mut B,
+) -> Result<Vec<Change>, VPParseError> {
+    Ok(diff_entries(&hash_entries(old)?, &hash_entries(new)?))
+}
+
+/// Work out what changed between two sets of entries, sorted by path.
+/// Paths are compared ignoring case, same as FSO.
+pub fn diff_entries(old: &[HashedEntry], new: &[HashedEntry]) -> Vec<Change> {
+    let old_map: HashMap<VPPath, &HashedEntry> =
+        old.iter().map(|e| (VPPath::from(&e.path), e)).collect();
+    let new_map: HashMap<VPPath, &HashedEntry> =
+        new.iter().map(|e| (VPPath::from(&e.path), e)).collect();
+
+    let mut changes = Vec::new();
+    let mut removed: Vec<&HashedEntry> = Vec::new();
+    for (path, old_entry) in old_map.iter() {
+        match new_map.get(path) {
+            None => removed.push(old_entry),
+            Some(new_entry) if new_entry.sha256 != old_entry.sha256 => {
+                changes.push(Change::Modified {
+                    old: (*old_entry).clone(),
+                    new: (*new_entry).clone(),
+                })
+            }
+            Some(_) => (),
+        }
+    }
+    let mut added: Vec<&HashedEntry> = new_map
+        .iter()
+        .filter(|(path, _)| !old_map.contains_key(*path))
+        .map(|(_, e)| *e)
+        .collect();
+
+    // Anything removed whose contents turned up somewhere new has been moved.
+    // Sorted first so which copy gets paired up is deterministic.
+    removed.sort_by(|a, b| a.path.cmp(&b.path));
+    added.sort_by(|a, b| a.path.cmp(&b.path));
+    for old_entry in removed {
+        match added.iter().position(|e| e.sha256 == old_entry.sha256) {
+            Some(pos) => changes.push(Change::Moved {
+                old: old_entry.clone(),
+                new: added.remove(pos).clone(),
+            }),
+            None => changes.push(Change::Removed(old_entry.clone())),
+        }
+    }
+    changes.extend(added.into_iter().map(|e| Change::Added(e.clone())));
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::VPWriter;
+    use std::io::Cursor;
+
+    fn vp(files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut writer = VPWriter::new(Cursor::new(Vec::new())).unwrap();
+        for (path, data) in files {
+            writer.add_file(path, data, 0).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn no_changes() {
+        let mut vp = std::fs::File::open("./test_files/mv_radaricons.vp").unwrap();
+        let entries = hash_entries(&mut vp).unwrap();
+        assert_eq!(entries.len(), 24);
+        assert_eq!(
+            entries[0].hex_hash(),
+            "6ae766ca5f822b7661e0a7a191f333881d37585bc0aabf45af2eb6d0f99739cc"
+        );
+        assert!(diff_entries(&entries, &entries).is_empty());
+    }
+
+    #[test]
+    fn detect_changes() {
+        let mut old = vp(&[
+            ("data/tables/ships.tbl", b"#Ships"),
+            ("data/tables/weapons.tbl", b"#Weapons"),
+            ("data/maps/old.dds", b"DDS old"),
+            ("data/effects/fire.dds", b"DDS fire"),
+        ]);
+        let mut new = vp(&[
+            ("data/tables/ships.tbl", b"#Ships v2"),
+            ("data/tables/weapons.tbl", b"#Weapons"),
+            ("data/maps/new.dds", b"DDS new"),
+            ("data/effects/explosions/fire.dds", b"DDS fire"),
+        ]);
+        let changes = diff(&mut old, &mut new).unwrap();
+        let summary: Vec<(&str, &str)> = changes
+            .iter()
+            .map(|c| {
+                let kind = match c {
+                    Change::Added(_) => "added",
+                    Change::Removed(_) => "removed",
+                    Change::Modified { .. } => "modified",
+                    Change::Moved { .. } => "moved",
+                };
+                (kind, c.path())
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("moved", "data/effects/explosions/fire.dds"),
+                ("added", "data/maps/new.dds"),
+                ("removed", "data/maps/old.dds"),
+                ("modified", "data/tables/ships.tbl"),
+            ]
+        );
+        match &changes[3] {
+            Change::Modified { old, new } => assert_eq!((old.size, new.size), (6, 9)),
+            other => panic!("expected modification, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compression_isnt_a_change() {
+        let tbl = "$Name: GTF Ulysses\n".repeat(100).into_bytes();
+        let mut old = vp(&[("data/tables/ships.tbl", &tbl)]);
+        let mut writer = VPWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.set_compression(true);
+        writer.add_file("data/tables/ships.tbl", &tbl, 0).unwrap();
+        let mut new = writer.finish().unwrap();
+        assert!(diff(&mut old, &mut new).unwrap().is_empty());
+    }
+}
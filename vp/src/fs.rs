@@ -1,10 +1,11 @@
 use crate::parser;
-use crate::types::{VPDir, VPEntry, VPFile};
+use crate::types::{VPDir, VPFile, VPHeader, VPIndex, VPParseError};
 use std::path::Path;
 use std::{
     fs::File,
     io::{self, Read, Seek, SeekFrom},
     path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[cfg(feature = "tokio")]
@@ -12,53 +13,110 @@ use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncRead, AsyncSeek};
 
 
 
-pub fn index<T: Read + Seek>(handle: &mut T) -> std::io::Result<VPDir> {
+/// VP version we know how to read, FSO only supports this one.
+pub(crate) const VP_VERSION: u32 = 2;
+pub(crate) const HEADER_LEN: usize = 16;
+pub(crate) const INDEX_ENTRY_LEN: u64 = 44;
+
+pub fn index<T: Read + Seek>(handle: &mut T) -> Result<VPDir, VPParseError> {
+    let (_, vp_index, file_len) = read_index(handle)?;
+    build_tree(vp_index, file_len)
+}
+
+/// Read the header and the index entries as stored, along with the length of the file.
+pub(crate) fn read_index<T: Read + Seek>(
+    handle: &mut T,
+) -> Result<(VPHeader, Vec<VPIndex>, u64), VPParseError> {
+    let head = read_header(handle)?;
+    let file_len = handle.seek(SeekFrom::End(0))?;
+    let index_len = index_len(&head, file_len)?;
+
+    handle.seek(SeekFrom::Start(head.offset.into()))?;
+    let mut indexbuf = vec![0u8; index_len];
+    handle.read_exact(&mut indexbuf)?;
+    let vp_index = parse_index(&head, &indexbuf)?;
+    Ok((head, vp_index, file_len))
+}
+
+pub fn read_header<T: Read + Seek>(handle: &mut T) -> Result<VPHeader, VPParseError> {
     handle.seek(SeekFrom::Start(0))?;
-    let mut headbuf = vec![0u8; 16];
+    let mut headbuf = [0u8; HEADER_LEN];
     handle.read_exact(&mut headbuf)?;
-    // Using unwrap here, we can't pass error upwards with ?
-    // as headbuf would need to be passed up
-    // and would outlive the local variable.
-    // Should handle errors better, but it's a first try for now.
-    let (_, head) = parser::header(&headbuf).unwrap();
+    check_header(&headbuf)
+}
 
-    handle.seek(SeekFrom::Start(head.offset.into()))?;
+pub(crate) fn check_header(headbuf: &[u8; HEADER_LEN]) -> Result<VPHeader, VPParseError> {
+    let (_, head) = parser::header(headbuf)
+        .map_err(|_| VPParseError::BadMagic(headbuf[..4].try_into().unwrap()))?;
+    if head.version != VP_VERSION {
+        return Err(VPParseError::UnsupportedVersion(head.version));
+    }
+    Ok(head)
+}
 
-    let mut indexbuf = vec![0u8; 0];
-    handle.read_to_end(&mut indexbuf)?;
+/// Check the index described by the header actually fits in the file, and return its length.
+pub(crate) fn index_len(head: &VPHeader, file_len: u64) -> Result<usize, VPParseError> {
+    let offset: u64 = head.offset.into();
+    if offset > file_len {
+        return Err(VPParseError::IndexPastEOF { offset, file_len });
+    }
+    let index_len = u64::from(head.entries) * INDEX_ENTRY_LEN;
+    if offset + index_len > file_len {
+        return Err(VPParseError::ShortIndex {
+            expected: head.entries,
+            found: (file_len - offset) / INDEX_ENTRY_LEN,
+        });
+    }
+    // At most 4G entries * 44 bytes, which we've just checked is in the file.
+    Ok(index_len.try_into().unwrap())
+}
 
-    // As before, .unwrap() because we can't pass indexbuf up.
-    let (_, vp_index) = parser::indicies(&indexbuf).unwrap();
-    Ok(VPDir::from(vp_index))
+pub(crate) fn parse_index(head: &VPHeader, indexbuf: &[u8]) -> Result<Vec<VPIndex>, VPParseError> {
+    let (_, vp_index) =
+        parser::indicies(indexbuf, head.entries).map_err(|_| VPParseError::ShortIndex {
+            expected: head.entries,
+            found: indexbuf.len() as u64 / INDEX_ENTRY_LEN,
+        })?;
+    Ok(vp_index)
 }
 
+pub(crate) fn build_tree(vp_index: Vec<VPIndex>, file_len: u64) -> Result<VPDir, VPParseError> {
+    for vpi in vp_index.iter().filter(|vpi| vpi.size != 0) {
+        let (offset, size) = (u64::from(vpi.fileoffset), u64::from(vpi.size));
+        if offset + size > file_len {
+            return Err(VPParseError::EntryPastEOF {
+                name: VPFile::from(vpi.clone()).name,
+                offset,
+                size,
+                file_len,
+            });
+        }
+    }
+    VPDir::try_from(vp_index)
+}
+
+/// Find a file from a path like `mod/data.vp/data/tables/ships.tbl`.
+/// The part inside the VP is matched ignoring case, as FSO does.
 pub fn read_entry<P: Into<PathBuf>>(path: P) -> io::Result<VPFile> {
     let path: PathBuf = path.into();
-    let (vp_filepath, mut folders) = split_path(&path)?;
-
-    let mut vpcontents: Vec<VPEntry> = index(&mut File::open(&vp_filepath)?)?.contents;
-    let file = folders
-        .pop()
-        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-    for folder in folders.into_iter() {
-        vpcontents = vpcontents
-            .into_iter()
-            .filter_map(|x| match x {
-                VPEntry::Dir(d) => Some(d),
-                _ => None,
-            })
-            .find(|x| x.name == folder)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?
-            .contents
+    let (vp_filepath, folders) = split_path(&path)?;
+
+    index(&mut File::open(&vp_filepath)?)?
+        .locate(&folders)
+        .map_err(|_| io::Error::from(io::ErrorKind::NotFound))
+}
+
+/// Timestamps in a VP are seconds since the Unix epoch.
+pub fn timestamp_to_time(timestamp: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.into())
+}
+
+/// Convert a time to a VP timestamp, clamped to what fits in 32 bits.
+pub fn time_to_timestamp(time: SystemTime) -> u32 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs().try_into().unwrap_or(u32::MAX),
+        Err(_) => 0,
     }
-    vpcontents
-        .into_iter()
-        .filter_map(|x| match x {
-            VPEntry::File(f) => Some(f),
-            _ => None,
-        })
-        .find(|x| x.name == file)
-        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
 }
 
 pub fn split_path(path: &Path) -> Result<(PathBuf, Vec<String>), std::io::Error> {
@@ -83,22 +141,27 @@ pub fn split_path(path: &Path) -> Result<(PathBuf, Vec<String>), std::io::Error>
 }
 
 #[cfg(feature = "tokio")]
-pub async fn async_index<T: AsyncRead + AsyncSeek + std::marker::Unpin>(handle: &mut T) -> Result<VPDir, Box<dyn std::error::Error>> {
+pub async fn async_index<T: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: &mut T,
+) -> Result<VPDir, VPParseError> {
+    let (_, vp_index, file_len) = async_read_index(handle).await?;
+    build_tree(vp_index, file_len)
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) async fn async_read_index<T: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: &mut T,
+) -> Result<(VPHeader, Vec<VPIndex>, u64), VPParseError> {
     handle.seek(SeekFrom::Start(0)).await?;
-    let mut headbuf = vec![0u8; 16];
+    let mut headbuf = [0u8; HEADER_LEN];
     handle.read_exact(&mut headbuf).await?;
-    // Using unwrap here, we can't pass error upwards with ?
-    // as headbuf would need to be passed up
-    // and would outlive the local variable.
-    // Should handle errors better, but it's a first try for now.
-    let (_, head) = parser::header(&headbuf).unwrap();
+    let head = check_header(&headbuf)?;
+    let file_len = handle.seek(SeekFrom::End(0)).await?;
+    let index_len = index_len(&head, file_len)?;
 
     handle.seek(SeekFrom::Start(head.offset.into())).await?;
-
-    let mut indexbuf = vec![0u8; 0];
-    handle.read_to_end(&mut indexbuf).await?;
-
-    // As before, .unwrap() because we can't pass indexbuf up.
-    let (_, vp_index) = parser::indicies(&indexbuf).unwrap();
-    Ok(VPDir::from(vp_index))
-}
\ No newline at end of file
+    let mut indexbuf = vec![0u8; index_len];
+    handle.read_exact(&mut indexbuf).await?;
+    let vp_index = parse_index(&head, &indexbuf)?;
+    Ok((head, vp_index, file_len))
+}
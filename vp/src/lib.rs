@@ -1,7 +1,15 @@
 pub mod compression;
+pub mod diff;
 pub mod fs;
+pub mod manifest;
+pub mod merge;
 pub mod parser;
+pub mod path;
+pub mod range;
+pub mod reader;
 pub mod types;
+pub mod validate;
+pub mod writer;
 #[cfg(test)]
 mod tests {
     use std::{
@@ -22,6 +30,16 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn read_header() {
+        let mut vp_file = File::open("./test_files/mv_radaricons.vp").unwrap();
+        let head = fs::read_header(&mut vp_file).unwrap();
+        assert_eq!(head.version, 2);
+        assert_eq!(head.entries, 30);
+        let mut not_vp = File::open("./test_files/radar-asteroid.dds").unwrap();
+        assert!(fs::read_header(&mut not_vp).is_err());
+    }
+
     #[test]
     fn read_entry() {
         let entry: VPFile =
@@ -50,4 +68,120 @@ mod tests {
         vp_file.read_exact(&mut vp_data).unwrap();
         assert_eq!(raw_data, vp_data)
     }
+
+    #[test]
+    fn glob_entries() {
+        let index = fs::index(&mut File::open("./test_files/mv_radaricons.vp").unwrap()).unwrap();
+        let tables = index.glob("data/tables/*.tbm");
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].name, "data/tables/radar-shp.tbm");
+        assert_eq!(index.glob("**/*.tbm").len(), 1);
+        assert_eq!(index.glob("data/hud/radar-c*.dds").len(), 6);
+        assert_eq!(index.glob("data/*/radar-cargo?.dds").len(), 1);
+        assert_eq!(index.glob("**").len(), index.flatten().len());
+        assert!(index.glob("data/*.dds").is_empty());
+    }
+
+    #[test]
+    fn locate_missing() {
+        let index = fs::index(&mut File::open("./test_files/mv_radaricons.vp").unwrap()).unwrap();
+        let path = |p: &str| p.split('/').map(String::from).collect::<Vec<_>>();
+        assert!(index.locate("").is_err());
+        // Directories and paths through files aren't files.
+        assert!(index.locate(&path("data/hud")).is_err());
+        assert!(index
+            .locate(&path("data/hud/radar-asteroid.dds/x"))
+            .is_err());
+        assert!(index.locate(&path("data/hud/radar-asteroid.dds")).is_ok());
+    }
+
+    #[test]
+    fn locate_ignores_case() {
+        let index = fs::index(&mut File::open("./test_files/mv_radaricons.vp").unwrap()).unwrap();
+        let entry = index.locate("data/hud/radar-asteroid.dds").unwrap();
+        // Paths from mods authored on Windows.
+        for path in [
+            "Data\\HUD\\Radar-Asteroid.DDS",
+            "/DATA/hud//radar-asteroid.dds",
+        ] {
+            assert_eq!(index.locate(path).unwrap().fileoffset, entry.fileoffset);
+        }
+        let entry =
+            fs::read_entry("./test_files/mv_radaricons.vp/Data/Hud/RADAR-asteroid.dds").unwrap();
+        assert_eq!(entry.name, "radar-asteroid.dds");
+        assert_eq!(index.glob("DATA/Tables/*.TBM").len(), 1);
+    }
+
+    // Hand build a VP with 4 bytes of data and whatever index we're given.
+    fn raw_vp(entries: &[(u32, u32, &str)]) -> Vec<u8> {
+        use crate::types::{VPHeader, VPIndex};
+        let mut out = writer::header_bytes(&VPHeader {
+            version: 2,
+            offset: 20,
+            entries: entries.len() as u32,
+        })
+        .to_vec();
+        out.extend(b"data");
+        for &(fileoffset, size, name) in entries {
+            out.extend(writer::index_entry_bytes(&VPIndex {
+                fileoffset,
+                size,
+                name: writer::encode_name(name).unwrap(),
+                timestamp: 0,
+            }));
+        }
+        out
+    }
+
+    #[test]
+    fn detect_corrupt_vps() {
+        use crate::types::VPParseError;
+        use std::io::Cursor;
+        let parse = |buf: Vec<u8>| fs::index(&mut Cursor::new(buf));
+
+        assert!(parse(raw_vp(&[(0, 0, "data"), (16, 4, "a.tbl"), (0, 0, "..")])).is_ok());
+
+        let not_vp = read("./test_files/radar-asteroid.dds").unwrap();
+        assert!(matches!(parse(not_vp), Err(VPParseError::BadMagic(m)) if &m == b"DDS "));
+
+        let mut version = raw_vp(&[(16, 4, "a.tbl")]);
+        version[4] = 3;
+        assert!(matches!(
+            parse(version),
+            Err(VPParseError::UnsupportedVersion(3))
+        ));
+
+        let mut offset = raw_vp(&[(16, 4, "a.tbl")]);
+        offset[8] = 200;
+        assert!(matches!(
+            parse(offset),
+            Err(VPParseError::IndexPastEOF { offset: 200, .. })
+        ));
+
+        let vp = read("./test_files/mv_radaricons.vp").unwrap();
+        let truncated = vp[..vp.len() - 10].to_vec();
+        assert!(matches!(
+            parse(truncated),
+            Err(VPParseError::ShortIndex {
+                expected: 30,
+                found: 29
+            })
+        ));
+
+        let stray = raw_vp(&[(16, 4, "a.tbl"), (0, 0, "..")]);
+        assert!(matches!(parse(stray), Err(VPParseError::UnbalancedDirs)));
+        let unclosed = raw_vp(&[(0, 0, "data"), (16, 4, "a.tbl")]);
+        assert!(matches!(parse(unclosed), Err(VPParseError::UnbalancedDirs)));
+
+        let past_eof = raw_vp(&[(16, 4, "a.tbl"), (18, 4000, "b.tbl")]);
+        assert!(matches!(
+            parse(past_eof),
+            Err(VPParseError::EntryPastEOF { name, .. }) if name == "b.tbl"
+        ));
+        // Too short to even have a header.
+        assert!(matches!(
+            parse(b"VPVP".to_vec()),
+            Err(VPParseError::IOError(_))
+        ));
+    }
 }
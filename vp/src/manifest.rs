@@ -0,0 +1,226 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::compression::maybe_decompress;
+use crate::fs;
+use crate::path::VPPath;
+use crate::types::{VPDir, VPParseError};
+use crate::writer::{PackOptions, VPWriter};
+
+/// A file in a flat listing of a VP, named by its full path inside it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListingEntry {
+    pub path: String,
+    /// Size as stored, which is the compressed size for LZ41 entries.
+    pub size: u64,
+    pub timestamp: u32,
+    /// SHA-256 of the decompressed contents, if they've been hashed.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none", with = "hex_sha256")
+    )]
+    pub sha256: Option<[u8; 32]>,
+}
+
+/// The layout of a VP, either as the directory tree or as a flat listing.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum Layout {
+    Tree(VPDir),
+    Listing(Vec<ListingEntry>),
+}
+
+impl Layout {
+    pub fn into_listing(self) -> Vec<ListingEntry> {
+        match self {
+            Layout::Tree(dir) => listing(&dir),
+            Layout::Listing(entries) => entries,
+        }
+    }
+}
+
+/// Every file under `dir`, in index order.
+pub fn listing(dir: &VPDir) -> Vec<ListingEntry> {
+    dir.files()
+        .into_iter()
+        .map(|f| ListingEntry {
+            path: f.name,
+            size: f.size,
+            timestamp: f.timestamp,
+            sha256: None,
+        })
+        .collect()
+}
+
+/// List every file in a VP along with the hash of its contents.
+pub fn hashed_listing<T: Read + Seek>(handle: &mut T) -> Result<Vec<ListingEntry>, VPParseError> {
+    let index = fs::index(handle)?;
+    let mut entries = listing(&index);
+    let files = index.flatten();
+    for (entry, file) in entries.iter_mut().zip(files) {
+        handle.seek(SeekFrom::Start(file.fileoffset))?;
+        let mut buf = vec![0u8; file.size as usize];
+        handle.read_exact(&mut buf)?;
+        entry.sha256 = Some(Sha256::digest(maybe_decompress(buf)?).into());
+    }
+    Ok(entries)
+}
+
+/// Write a VP laid out as `entries` describes, in the same order and with the same timestamps.
+/// `contents` supplies the decompressed data for each entry,
+/// which is checked against the entry's hash when it has one.
+pub fn write_listing<W, F>(
+    entries: &[ListingEntry],
+    out: W,
+    opts: &PackOptions,
+    mut contents: F,
+) -> io::Result<W>
+where
+    W: Write + Seek,
+    F: FnMut(&ListingEntry) -> io::Result<Vec<u8>>,
+{
+    let mut writer = VPWriter::new(out)?;
+    writer.set_compression(opts.compress);
+    for entry in entries {
+        let data = contents(entry)?;
+        if let Some(expected) = entry.sha256 {
+            let found: [u8; 32] = Sha256::digest(&data).into();
+            if found != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("contents of {} don't match its hash", entry.path),
+                ));
+            }
+        }
+        let timestamp = opts.timestamp.unwrap_or(entry.timestamp);
+        writer.add_file(&entry.path, &data, timestamp)?;
+    }
+    writer.finish()
+}
+
+/// Contents for `write_listing` from loose files laid out under `root` the same way as the VP.
+pub fn dir_contents(root: &Path) -> impl FnMut(&ListingEntry) -> io::Result<Vec<u8>> + '_ {
+    move |entry| {
+        let path = VPPath::from(&entry.path).to_fs_path(root).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a valid path", entry.path),
+            )
+        })?;
+        std::fs::read(path)
+    }
+}
+
+// Hashes are written as hex strings, the same as everywhere else they're shown.
+#[cfg(feature = "serde")]
+mod hex_sha256 {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &Option<[u8; 32]>, s: S) -> Result<S::Ok, S::Error> {
+        match hash {
+            Some(hash) => {
+                let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+                s.serialize_some(&hex)
+            }
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<[u8; 32]>, D::Error> {
+        let hex = match Option::<String>::deserialize(d)? {
+            Some(hex) => hex,
+            None => return Ok(None),
+        };
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(D::Error::custom("SHA-256 hashes are 64 hex digits"));
+        }
+        let mut hash = [0u8; 32];
+        for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).unwrap();
+            *byte = u8::from_str_radix(digits, 16).map_err(D::Error::custom)?;
+        }
+        Ok(Some(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::hash_entries;
+    use std::fs::File;
+    use std::io::Cursor;
+
+    #[test]
+    fn rebuild_from_listing() {
+        let mut vp = File::open("./test_files/mv_radaricons.vp").unwrap();
+        let entries = hashed_listing(&mut vp).unwrap();
+        assert_eq!(entries.len(), 24);
+        assert_eq!(entries[0].path, "data/hud/radar-asteroid.dds");
+
+        // Serve contents straight out of the original VP.
+        let index = fs::index(&mut vp).unwrap();
+        let contents = |entry: &ListingEntry| {
+            let file = index.locate(entry.path.as_str()).unwrap();
+            let mut buf = vec![0u8; file.size as usize];
+            vp.seek(SeekFrom::Start(file.fileoffset))?;
+            vp.read_exact(&mut buf)?;
+            Ok(buf)
+        };
+        let opts = PackOptions::default();
+        let mut out = write_listing(&entries, Cursor::new(Vec::new()), &opts, contents).unwrap();
+        let mut orig = File::open("./test_files/mv_radaricons.vp").unwrap();
+        assert_eq!(
+            hash_entries(&mut out).unwrap(),
+            hash_entries(&mut orig).unwrap()
+        );
+        assert_eq!(hashed_listing(&mut out).unwrap(), entries);
+    }
+
+    #[test]
+    fn reject_wrong_contents() {
+        let entries = [ListingEntry {
+            path: "data/tables/ships.tbl".to_string(),
+            size: 6,
+            timestamp: 0,
+            sha256: Some([0; 32]),
+        }];
+        let opts = PackOptions::default();
+        let res = write_listing(&entries, Cursor::new(Vec::new()), &opts, |_| {
+            Ok(b"#Ships".to_vec())
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_layouts() {
+        let mut vp = File::open("./test_files/mv_radaricons.vp").unwrap();
+        let entries = hashed_listing(&mut vp).unwrap();
+        let json = serde_json::to_string(&entries).unwrap();
+        assert!(json.contains(&format!(
+            "\"sha256\":\"{}\"",
+            hash_entries(&mut vp).unwrap()[0].hex_hash()
+        )));
+        match serde_json::from_str(&json).unwrap() {
+            Layout::Listing(parsed) => assert_eq!(parsed, entries),
+            Layout::Tree(_) => panic!("listing parsed as a tree"),
+        }
+
+        // Listings without hashes leave the field out.
+        let index = fs::index(&mut vp).unwrap();
+        let json = serde_json::to_string(&listing(&index)).unwrap();
+        assert!(!json.contains("sha256"));
+
+        let json = serde_json::to_string(&index).unwrap();
+        assert!(json.contains("\"type\":\"dir\""));
+        match serde_json::from_str(&json).unwrap() {
+            Layout::Tree(tree) => assert_eq!(listing(&tree), listing(&index)),
+            Layout::Listing(_) => panic!("tree parsed as a listing"),
+        }
+    }
+}
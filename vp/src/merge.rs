@@ -0,0 +1,172 @@
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::fs;
+use crate::path::VPPath;
+use crate::types::{VPDir, VPFile};
+use crate::writer::{dir_entries, PackOptions, VPWriter};
+
+/// Something to take files from when merging.
+#[derive(Clone, Debug)]
+pub enum MergeInput {
+    VP(PathBuf),
+    /// A loose directory, treated as the root of a VP, so it should usually contain `data`.
+    Dir(PathBuf),
+}
+
+impl MergeInput {
+    /// VPs are files, anything else is taken as a directory.
+    pub fn from_path(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if path.is_dir() {
+            MergeInput::Dir(path)
+        } else {
+            MergeInput::VP(path)
+        }
+    }
+
+    /// Index of the files this input provides, with sizes as stored.
+    /// Loose files are timestamped with their modification time.
+    pub fn index(&self) -> io::Result<VPDir> {
+        match self {
+            MergeInput::VP(path) => Ok(fs::index(&mut File::open(path)?)?),
+            MergeInput::Dir(path) => {
+                let mut index = VPDir::default();
+                for (vp_path, fs_path) in dir_entries(path)? {
+                    let mut parts: Vec<String> = vp_path.split('/').map(String::from).collect();
+                    let name = parts.pop().unwrap();
+                    let meta = std::fs::metadata(fs_path)?;
+                    let file = VPFile {
+                        fileoffset: 0,
+                        size: meta.len(),
+                        name,
+                        timestamp: fs::time_to_timestamp(meta.modified()?),
+                    };
+                    index
+                        .insert(&parts, file)
+                        .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()))?;
+                }
+                Ok(index)
+            }
+        }
+    }
+}
+
+/// A file from a later input replacing one from an earlier input.
+/// Inputs are referred to by their position in the list given to `merge`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+    pub path: String,
+    pub old: usize,
+    pub new: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MergeReport {
+    pub files: usize,
+    pub overrides: Vec<Override>,
+}
+
+/// Combine several VPs and directories into a single VP.
+/// When more than one input has a file at the same path, ignoring case as FSO does,
+/// the last one wins. It takes the place of the first in the index,
+/// so patches don't shuffle the order of the files they replace.
+/// Files keep their timestamps unless `opts` gives a fixed one.
+pub fn merge<W: Write + Seek>(
+    inputs: &[MergeInput],
+    out: W,
+    opts: &PackOptions,
+) -> io::Result<(W, MergeReport)> {
+    let mut report = MergeReport::default();
+    // (input, file) pairs, in the order they'll be written.
+    let mut files: Vec<(usize, VPFile)> = Vec::new();
+    let mut positions: HashMap<VPPath, usize> = HashMap::new();
+    for (input, source) in inputs.iter().enumerate() {
+        for file in source.index()?.files() {
+            match positions.get(&VPPath::from(&file.name)) {
+                Some(&pos) => {
+                    report.overrides.push(Override {
+                        path: file.name.clone(),
+                        old: files[pos].0,
+                        new: input,
+                    });
+                    files[pos] = (input, file);
+                }
+                None => {
+                    positions.insert(VPPath::from(&file.name), files.len());
+                    files.push((input, file));
+                }
+            }
+        }
+    }
+
+    let mut writer = VPWriter::new(out)?;
+    writer.set_compression(opts.compress);
+    // Only open each VP once, there's a good chance we'll read most of it.
+    let mut vps: HashMap<usize, BufReader<File>> = HashMap::new();
+    for (input, file) in files.iter() {
+        let timestamp = opts.timestamp.unwrap_or(file.timestamp);
+        match &inputs[*input] {
+            MergeInput::VP(path) => {
+                let vp = match vps.entry(*input) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(BufReader::new(File::open(path)?)),
+                };
+                vp.seek(SeekFrom::Start(file.fileoffset))?;
+                writer.add_reader(&file.name, &mut vp.take(file.size), timestamp)?;
+            }
+            MergeInput::Dir(root) => {
+                let mut src = File::open(root.join(&file.name))?;
+                writer.add_reader(&file.name, &mut src, timestamp)?;
+            }
+        }
+        report.files += 1;
+    }
+    Ok((writer.finish()?, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::hash_entries;
+    use std::io::Cursor;
+
+    #[test]
+    fn patch_overrides_base() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("base.vp");
+        let patch = tmp.path().join("patch");
+        std::fs::create_dir_all(patch.join("data/tables")).unwrap();
+        std::fs::copy("./test_files/mv_radaricons.vp", &base).unwrap();
+        std::fs::write(patch.join("data/tables/Radar-Shp.tbm"), b"#patched").unwrap();
+        std::fs::write(patch.join("data/tables/new.tbm"), b"#new").unwrap();
+
+        let inputs = [MergeInput::from_path(&base), MergeInput::from_path(&patch)];
+        let opts = PackOptions::default();
+        let (mut out, report) = merge(&inputs, Cursor::new(Vec::new()), &opts).unwrap();
+
+        assert_eq!(report.files, 25);
+        assert_eq!(
+            report.overrides,
+            vec![Override {
+                path: "data/tables/Radar-Shp.tbm".to_string(),
+                old: 0,
+                new: 1
+            }]
+        );
+        let entries = hash_entries(&mut out).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths[0], "data/hud/radar-asteroid.dds");
+        // Replaced file keeps its place, new files go at the end of their directory.
+        assert_eq!(
+            &paths[23..],
+            ["data/tables/Radar-Shp.tbm", "data/tables/new.tbm"]
+        );
+        assert_eq!(entries[23].size, 8);
+        // Everything else came across untouched.
+        let mut orig = File::open("./test_files/mv_radaricons.vp").unwrap();
+        assert_eq!(hash_entries(&mut orig).unwrap()[..23], entries[..23]);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::types::{VPHeader, VPIndex};
 use nom;
 use nom::bytes::complete::*;
-use nom::multi::many0;
+use nom::multi::count;
 use nom::number::complete::le_u32;
 use nom::IResult;
 
@@ -37,8 +37,8 @@ fn index_entry(input: &[u8]) -> IResult<&[u8], VPIndex> {
     ))
 }
 
-pub(crate) fn indicies(input: &[u8]) -> IResult<&[u8], Vec<VPIndex>> {
-    many0(index_entry)(input)
+pub(crate) fn indicies(input: &[u8], entries: u32) -> IResult<&[u8], Vec<VPIndex>> {
+    count(index_entry, entries as usize)(input)
 }
 #[cfg(test)]
 mod tests {
@@ -100,8 +100,9 @@ mod tests {
         \x00\x00\x00\x00\xB8\xC2\x5C\x4C\xD4\x7B\x00\x00\x00\x00\x00\x00\x2E\x2E\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
         \x00\xD4\x7B\x00\x00\x00\x00\x00\x00\x2E\x2E\x00\x00\x00\x00\x32\x00\x00\x00\x00\x01\x60\xF4\x18\x00\x50\xF5\x18\x00\x78\xFF\x18\x00\xDD\x03\x39\x77\x5C\x83\x01\x95\x00\x00\x00\x00\x00";
 
-        let index = indicies(indexbuf);
+        let index = indicies(indexbuf, 30);
         println!("{:?}", index);
-        assert!(index.is_ok())
+        assert!(index.is_ok());
+        assert!(indicies(&indexbuf[..44 * 29], 30).is_err());
     }
 }
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Longest name that fits in an index entry, leaving room for the terminating null.
+pub const MAX_NAME_LEN: usize = 31;
+
+/// Windows-1252 characters for bytes 0x80-0x9F, which Latin-1 leaves as control codes.
+/// The gaps Windows doesn't assign fall back to the control code.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+/// Decode a null terminated name from the index.
+/// Names are read as UTF-8. Anything that isn't valid UTF-8 was written by an old
+/// Windows tool in the ANSI codepage, so it's decoded as Windows-1252 instead.
+pub fn decode_name(raw: &[u8]) -> String {
+    let raw = match raw.iter().position(|&b| b == 0) {
+        Some(end) => &raw[..end],
+        None => raw,
+    };
+    match std::str::from_utf8(raw) {
+        Ok(name) => name.to_string(),
+        Err(_) => raw
+            .iter()
+            .map(|&b| match b {
+                0x80..=0x9f => CP1252_HIGH[usize::from(b - 0x80)],
+                _ => char::from(b),
+            })
+            .collect(),
+    }
+}
+
+/// Names are stored as 32 byte, null terminated strings, always written as UTF-8.
+/// That leaves 31 bytes for the name, so non-ASCII names get fewer characters.
+pub fn encode_name(name: &str) -> io::Result<[u8; 32]> {
+    let mut buf = [0u8; 32];
+    if name.len() > MAX_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is longer than {} bytes", name, MAX_NAME_LEN),
+        ));
+    }
+    if name.contains(['\0', '/', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can't be encoded in a VP", name),
+        ));
+    }
+    buf[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(buf)
+}
+
+/// Whether two names refer to the same entry.
+/// FSO compares names with `stricmp`, so only ASCII letters are folded.
+pub fn names_match(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// A path to an entry inside a VP, such as `data/tables/ships.tbl`.
+/// Either separator is accepted and empty components are dropped, so `\Data\Tables\`
+/// is the same path as `data/tables`. Paths compare and hash ignoring case, the same
+/// way FSO resolves them, but keep the case they were created with for display.
+#[derive(Clone, Debug, Default)]
+pub struct VPPath {
+    parts: Vec<String>,
+}
+
+impl VPPath {
+    pub fn new(path: &str) -> Self {
+        Self {
+            parts: path
+                .split(['/', '\\'])
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    pub fn parts(&self) -> &[String] {
+        &self.parts
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// The file name and the directories leading to it.
+    pub fn split_last(&self) -> Option<(&String, &[String])> {
+        self.parts.split_last()
+    }
+
+    /// Where this path would be under `root` on disk.
+    /// `None` for empty paths and ones with `.` or `..` components,
+    /// so paths from someone else's VP can't point outside `root`.
+    pub fn to_fs_path(&self, root: impl AsRef<Path>) -> Option<PathBuf> {
+        if self.is_empty() || self.parts.iter().any(|p| p == "." || p == "..") {
+            return None;
+        }
+        Some(
+            self.parts
+                .iter()
+                .fold(root.as_ref().to_path_buf(), |path, part| path.join(part)),
+        )
+    }
+
+    /// Normalised form of the path, the same for every spelling FSO treats as equal.
+    pub fn key(&self) -> String {
+        self.parts
+            .iter()
+            .map(|p| p.to_ascii_lowercase())
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+}
+
+impl PartialEq for VPPath {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts.len() == other.parts.len()
+            && self
+                .parts
+                .iter()
+                .zip(other.parts.iter())
+                .all(|(a, b)| names_match(a, b))
+    }
+}
+
+impl Eq for VPPath {}
+
+impl Hash for VPPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+impl fmt::Display for VPPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.parts.join("/"))
+    }
+}
+
+impl From<&str> for VPPath {
+    fn from(path: &str) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<&String> for VPPath {
+    fn from(path: &String) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<String> for VPPath {
+    fn from(path: String) -> Self {
+        Self::new(&path)
+    }
+}
+
+impl From<&[String]> for VPPath {
+    fn from(parts: &[String]) -> Self {
+        // Components may themselves contain separators, e.g. from a Windows path.
+        Self::new(&parts.join("/"))
+    }
+}
+
+impl From<&Vec<String>> for VPPath {
+    fn from(parts: &Vec<String>) -> Self {
+        Self::from(parts.as_slice())
+    }
+}
+
+impl<const N: usize> From<&[String; N]> for VPPath {
+    fn from(parts: &[String; N]) -> Self {
+        Self::from(parts.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn decode_names() {
+        let mut raw = [0u8; 32];
+        raw[..9].copy_from_slice(b"ships.tbl");
+        assert_eq!(decode_name(&raw), "ships.tbl");
+
+        let utf8 = encode_name("bäse.tbm").unwrap();
+        assert_eq!(decode_name(&utf8), "bäse.tbm");
+
+        // Windows-1252 from an old tool, not valid UTF-8.
+        assert_eq!(decode_name(b"b\xe4se\x80.tbm\0"), "bäse€.tbm");
+        // No terminator at all still decodes.
+        assert_eq!(decode_name(b"abc"), "abc");
+    }
+
+    #[test]
+    fn encode_names() {
+        assert!(encode_name(&"a".repeat(31)).is_ok());
+        assert!(encode_name(&"a".repeat(32)).is_err());
+        // 16 two byte characters don't fit.
+        assert!(encode_name(&"ä".repeat(16)).is_err());
+        assert!(encode_name("data/ships.tbl").is_err());
+        assert!(encode_name("data\\ships.tbl").is_err());
+    }
+
+    #[test]
+    fn paths_ignore_case_and_separators() {
+        let path = VPPath::new("\\Data\\Tables\\Ships.TBL");
+        assert_eq!(path, VPPath::new("data/tables/ships.tbl"));
+        assert_eq!(path, VPPath::new("/data//tables/ships.tbl/"));
+        assert_ne!(path, VPPath::new("data/ships.tbl"));
+        assert_eq!(path.to_string(), "Data/Tables/Ships.TBL");
+        assert_eq!(path.key(), "data/tables/ships.tbl");
+        // Only ASCII is folded, like FSO.
+        assert_ne!(VPPath::new("Ä.tbl"), VPPath::new("ä.tbl"));
+
+        assert_eq!(
+            path.to_fs_path("out"),
+            Some(PathBuf::from("out/Data/Tables/Ships.TBL"))
+        );
+        assert_eq!(VPPath::new("data/../../secrets").to_fs_path("out"), None);
+        assert_eq!(VPPath::new("/").to_fs_path("out"), None);
+
+        let map: HashMap<VPPath, u32> = [(path, 1)].into_iter().collect();
+        assert_eq!(map.get(&VPPath::new("DATA/tables\\ships.tbl")), Some(&1));
+    }
+}
@@ -0,0 +1,119 @@
+use std::future::Future;
+use std::io;
+
+use bytes::Bytes;
+
+use crate::compression::maybe_decompress;
+use crate::fs::{build_tree, check_header, index_len, parse_index, HEADER_LEN};
+use crate::types::{VPDir, VPFile, VPParseError};
+
+/// Somewhere a VP can be read from a byte range at a time, such as a server that
+/// supports HTTP `Range` requests. Reading the index and a few entries this way
+/// avoids fetching the whole VP when only some of it is needed.
+pub trait RangeRead {
+    /// Length of the whole VP.
+    fn size(&mut self) -> impl Future<Output = io::Result<u64>> + Send;
+
+    /// Read `len` bytes starting `offset` bytes into the VP.
+    fn read_range(
+        &mut self,
+        offset: u64,
+        len: usize,
+    ) -> impl Future<Output = io::Result<Bytes>> + Send;
+}
+
+/// Read a VP's index with two ranges, one for the header and one for the index itself.
+pub async fn range_index<R: RangeRead>(reader: &mut R) -> Result<VPDir, VPParseError> {
+    let headbuf = read_exact_range(reader, 0, HEADER_LEN).await?;
+    let head = check_header(&headbuf[..].try_into().unwrap())?;
+    let file_len = reader.size().await?;
+    let index_len = index_len(&head, file_len)?;
+
+    let indexbuf = read_exact_range(reader, head.offset.into(), index_len).await?;
+    let vp_index = parse_index(&head, &indexbuf)?;
+    build_tree(vp_index, file_len)
+}
+
+/// Fetch an entry found with `range_index`, decompressed if it's stored LZ41 compressed.
+pub async fn range_read_entry<R: RangeRead>(reader: &mut R, file: &VPFile) -> io::Result<Vec<u8>> {
+    let len =
+        usize::try_from(file.size).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    let buf = read_exact_range(reader, file.fileoffset, len).await?;
+    maybe_decompress(buf.to_vec())
+}
+
+// Readers may hand back less than asked for at the end of the file, which is never what we want.
+async fn read_exact_range<R: RangeRead>(
+    reader: &mut R,
+    offset: u64,
+    len: usize,
+) -> io::Result<Bytes> {
+    let buf = reader.read_range(offset, len).await?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::writer::VPWriter;
+
+    // An in memory VP that remembers which ranges were asked for.
+    struct LoggedRanges {
+        data: Bytes,
+        reads: Vec<(u64, usize)>,
+    }
+
+    impl RangeRead for LoggedRanges {
+        async fn size(&mut self) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        async fn read_range(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+            self.reads.push((offset, len));
+            let start = std::cmp::min(offset as usize, self.data.len());
+            let end = std::cmp::min(start + len, self.data.len());
+            Ok(self.data.slice(start..end))
+        }
+    }
+
+    #[tokio::test]
+    async fn only_fetch_what_we_need() {
+        // Stored as is, so it's most of the VP.
+        let music = vec![7u8; 100_000];
+        let tbl = "$Name: GTF Ulysses\n".repeat(1000).into_bytes();
+        let mut writer = VPWriter::new(io::Cursor::new(Vec::new())).unwrap();
+        writer.set_compression(true);
+        writer.add_file("data/music/big.ogg", &music, 0).unwrap();
+        writer.add_file("data/tables/ships.tbl", &tbl, 0).unwrap();
+        let data = Bytes::from(writer.finish().unwrap().into_inner());
+        let mut reader = LoggedRanges {
+            data: data.clone(),
+            reads: Vec::new(),
+        };
+
+        let index = range_index(&mut reader).await.unwrap();
+        assert_eq!(index.flatten().len(), 2);
+        let file = index.locate("data/tables/ships.tbl").unwrap();
+        assert_eq!(range_read_entry(&mut reader, &file).await.unwrap(), tbl);
+
+        assert_eq!(reader.reads.len(), 3);
+        let fetched: usize = reader.reads.iter().map(|(_, len)| len).sum();
+        assert!(fetched < data.len() / 2);
+    }
+
+    #[tokio::test]
+    async fn truncated_vp() {
+        let vp = std::fs::read("./test_files/mv_radaricons.vp").unwrap();
+        let mut reader = LoggedRanges {
+            data: Bytes::from(vp).slice(..1000),
+            reads: Vec::new(),
+        };
+        assert!(matches!(
+            range_index(&mut reader).await,
+            Err(VPParseError::IndexPastEOF { .. })
+        ));
+    }
+}
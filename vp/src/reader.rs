@@ -0,0 +1,248 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+
+use crate::compression::{decompress_block, is_compressed, LZ4Info, FOOTER_LEN, LZ41_MAGIC};
+use crate::fs;
+use crate::path::VPPath;
+use crate::types::{VPDir, VPFile, VPParseError};
+
+/// Largest chunk handed out when streaming an entry, the same as the LZ41 block size.
+pub const CHUNK_LEN: usize = 65536;
+
+enum Source {
+    /// Positional reads, which don't share a cursor so don't need a lock.
+    File(File),
+    /// The whole archive mapped into memory, entries are slices of the map.
+    #[cfg(feature = "mmap")]
+    Mapped(Bytes),
+}
+
+/// An open VP that any number of threads can read entries from at once.
+/// Nothing seeks, so readers can share one archive instead of each opening their own.
+pub struct VPArchive {
+    path: PathBuf,
+    source: Source,
+    index: VPDir,
+    files: HashMap<VPPath, VPFile>,
+}
+
+impl VPArchive {
+    /// Open a VP, reading entries with `pread`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, VPParseError> {
+        let mut file = File::open(&path)?;
+        let index = fs::index(&mut file)?;
+        Ok(Self::new(path.as_ref(), Source::File(file), index))
+    }
+
+    /// Open a VP by mapping it into memory, so reading an entry doesn't copy it.
+    ///
+    /// # Safety
+    /// The VP must not be truncated or written to while any `Bytes` read from it are alive.
+    /// Replacing it by renaming another file over it is fine.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mmap(path: impl AsRef<Path>) -> Result<Self, VPParseError> {
+        let file = File::open(&path)?;
+        let map = memmap2::Mmap::map(&file)?;
+        let index = fs::index(&mut io::Cursor::new(&map[..]))?;
+        let source = Source::Mapped(Bytes::from_owner(map));
+        Ok(Self::new(path.as_ref(), source, index))
+    }
+
+    fn new(path: &Path, source: Source, index: VPDir) -> Self {
+        let files = index
+            .flatten()
+            .into_iter()
+            .map(|f| (VPPath::from(&f.name), f))
+            .collect();
+        Self {
+            path: path.to_path_buf(),
+            source,
+            index,
+            files,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn index(&self) -> &VPDir {
+        &self.index
+    }
+
+    /// Look up a file, ignoring case and separators as FSO does.
+    pub fn entry(&self, path: impl Into<VPPath>) -> Option<&VPFile> {
+        self.files.get(&path.into())
+    }
+
+    /// Read `len` bytes starting `offset` bytes into the VP.
+    pub fn read_at(&self, offset: u64, len: usize) -> io::Result<Bytes> {
+        match &self.source {
+            Source::File(file) => {
+                let mut buf = vec![0u8; len];
+                read_exact_at(file, &mut buf, offset)?;
+                Ok(Bytes::from(buf))
+            }
+            #[cfg(feature = "mmap")]
+            Source::Mapped(map) => {
+                let start = usize::try_from(offset).map_err(|_| eof())?;
+                match start.checked_add(len) {
+                    Some(end) if end <= map.len() => Ok(map.slice(start..end)),
+                    _ => Err(eof()),
+                }
+            }
+        }
+    }
+
+    /// An entry as stored, which may be LZ41 compressed.
+    pub fn read_raw(&self, file: &VPFile) -> io::Result<Bytes> {
+        self.read_at(file.fileoffset, entry_len(file)?)
+    }
+
+    /// Stream an entry as stored, `CHUNK_LEN` bytes at a time.
+    pub fn raw_chunks<'a>(&'a self, file: &VPFile) -> impl Iterator<Item = io::Result<Bytes>> + 'a {
+        let end = file.fileoffset + file.size;
+        (file.fileoffset..end)
+            .step_by(CHUNK_LEN)
+            .map(move |offset| self.read_at(offset, min(CHUNK_LEN as u64, end - offset) as usize))
+    }
+
+    /// Stream the contents of an entry.
+    /// LZ41 entries are made up of independently compressed blocks,
+    /// so they're decompressed a block at a time instead of reading the whole entry.
+    pub fn chunks<'a>(
+        &'a self,
+        file: &VPFile,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<Bytes>> + 'a>> {
+        let magic = self.read_at(file.fileoffset, min(file.size, 4) as usize)?;
+        if !is_compressed(&magic) {
+            return Ok(Box::new(self.raw_chunks(file)));
+        }
+        let entry_len = entry_len(file)?;
+        if entry_len < LZ41_MAGIC.len() + FOOTER_LEN {
+            return Err(invalid());
+        }
+        let entry_end = file.fileoffset + file.size;
+        let footer = self.read_at(entry_end - FOOTER_LEN as u64, FOOTER_LEN)?;
+        let (_, info) = LZ4Info::parse(&footer).map_err(|_| invalid())?;
+        let table_len = info.table_len();
+        if table_len + FOOTER_LEN > entry_len {
+            return Err(invalid());
+        }
+        let table = self.read_at(entry_end - (FOOTER_LEN + table_len) as u64, table_len)?;
+        let offsets = info.parse_offsets(&table, entry_len)?;
+
+        let start = file.fileoffset;
+        Ok(Box::new((0..offsets.len() - 1).map(move |block| {
+            let compressed = self.read_at(
+                start + offsets[block] as u64,
+                offsets[block + 1] - offsets[block],
+            )?;
+            let decompressed = decompress_block(&compressed, info.block_len(block as u32))?;
+            Ok(Bytes::from(decompressed))
+        })))
+    }
+}
+
+fn entry_len(file: &VPFile) -> io::Result<usize> {
+    usize::try_from(file.size).map_err(|_| invalid())
+}
+
+fn invalid() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidData)
+}
+
+#[cfg(feature = "mmap")]
+fn eof() -> io::Error {
+    io::Error::from(io::ErrorKind::UnexpectedEof)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    // seek_read also moves the cursor, but every read here gives its own offset.
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::maybe_decompress;
+    use crate::writer::VPWriter;
+
+    fn check_entries(archive: &VPArchive) {
+        let expected = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        let file = archive.entry("Data\\HUD\\radar-asteroid.dds").unwrap();
+        assert_eq!(archive.read_raw(file).unwrap(), expected);
+        let chunks: Vec<Bytes> = archive.raw_chunks(file).map(Result::unwrap).collect();
+        assert_eq!(chunks.concat(), expected);
+        assert!(archive.entry("data/hud/missing.dds").is_none());
+        assert!(archive.read_at(u64::MAX - 1, 4).is_err());
+    }
+
+    #[test]
+    fn pread_entries() {
+        let archive = VPArchive::open("./test_files/mv_radaricons.vp").unwrap();
+        assert_eq!(archive.index().flatten().len(), 24);
+        check_entries(&archive);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_entries() {
+        // Nothing writes to the test files.
+        let archive = unsafe { VPArchive::open_mmap("./test_files/mv_radaricons.vp") }.unwrap();
+        check_entries(&archive);
+    }
+
+    #[test]
+    fn decompress_chunks() {
+        // Enough to need several blocks, with a short one at the end.
+        let tbl = "$Name: GTF Ulysses\n".repeat(10000).into_bytes();
+        let mut writer = VPWriter::new(io::Cursor::new(Vec::new())).unwrap();
+        writer.set_compression(true);
+        writer.add_file("data/tables/ships.tbl", &tbl, 0).unwrap();
+        writer
+            .add_file("data/tables/small.tbl", b"#Small", 0)
+            .unwrap();
+        let vp = writer.finish().unwrap().into_inner();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compressed.vp");
+        std::fs::write(&path, &vp).unwrap();
+        let archive = VPArchive::open(&path).unwrap();
+
+        let file = archive.entry("data/tables/ships.tbl").unwrap();
+        let raw = archive.read_raw(file).unwrap();
+        assert!(is_compressed(&raw));
+        let chunks: Vec<Bytes> = archive.chunks(file).unwrap().map(Result::unwrap).collect();
+        assert_eq!(chunks.len(), tbl.len().div_ceil(CHUNK_LEN));
+        assert_eq!(chunks.concat(), tbl);
+        assert_eq!(maybe_decompress(raw.to_vec()).unwrap(), tbl);
+
+        let small = archive.entry("data/tables/small.tbl").unwrap();
+        let chunks: Vec<Bytes> = archive.chunks(small).unwrap().map(Result::unwrap).collect();
+        assert_eq!(chunks.concat(), b"#Small");
+    }
+}
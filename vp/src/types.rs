@@ -1,7 +1,10 @@
-use std::{fmt::Display, slice::Iter};
+use std::{fmt::Display, io, slice::Iter};
+
+use crate::path::{decode_name, names_match, VPPath};
 
 // Header and Index for use with nom parser.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VPHeader {
     pub version: u32,
     pub offset: u32,
@@ -19,6 +22,7 @@ pub struct VPIndex {
 #[derive(Debug)]
 pub enum VPError {
     NotFound,
+    AlreadyExists,
 }
 
 impl Display for VPError {
@@ -29,41 +33,207 @@ impl Display for VPError {
 
 impl std::error::Error for VPError {}
 
+/// Everything that can go wrong reading the header and index of a VP.
+#[derive(Debug)]
+pub enum VPParseError {
+    IOError(io::Error),
+    /// File doesn't start with `VPVP`.
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u32),
+    IndexPastEOF {
+        offset: u64,
+        file_len: u64,
+    },
+    /// Header promises more index entries than fit between the index offset and EOF.
+    ShortIndex {
+        expected: u32,
+        found: u64,
+    },
+    /// A `..` with no directory to close, or directories still open at the end of the index.
+    UnbalancedDirs,
+    EntryPastEOF {
+        name: String,
+        offset: u64,
+        size: u64,
+        file_len: u64,
+    },
+}
+
+impl Display for VPParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VPParseError::IOError(e) => write!(f, "IO error reading VP: {}", e),
+            VPParseError::BadMagic(magic) => write!(f, "not a VP file, magic is {:?}", magic),
+            VPParseError::UnsupportedVersion(v) => write!(f, "unsupported VP version {}", v),
+            VPParseError::IndexPastEOF { offset, file_len } => write!(
+                f,
+                "index offset {} is beyond end of {} byte file",
+                offset, file_len
+            ),
+            VPParseError::ShortIndex { expected, found } => write!(
+                f,
+                "index should have {} entries, only room for {}",
+                expected, found
+            ),
+            VPParseError::UnbalancedDirs => write!(f, "unbalanced directories in index"),
+            VPParseError::EntryPastEOF {
+                name,
+                offset,
+                size,
+                file_len,
+            } => write!(
+                f,
+                "{} ({} bytes at {}) is beyond end of {} byte file",
+                name, size, offset, file_len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VPParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VPParseError::IOError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VPParseError {
+    fn from(e: io::Error) -> Self {
+        VPParseError::IOError(e)
+    }
+}
+
+impl From<VPParseError> for io::Error {
+    fn from(e: VPParseError) -> Self {
+        match e {
+            VPParseError::IOError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 // Entry, Dir and File for actually parsing into a directory structure.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "lowercase"))]
 pub enum VPEntry {
     File(VPFile),
     Dir(VPDir),
 }
 
 #[derive(Clone, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VPDir {
     pub name: String,
     pub contents: Vec<VPEntry>,
 }
 
 impl VPDir {
-    pub fn locate(&self, filepath: &[String]) -> Result<VPFile, VPError> {
-        let folder = &filepath[0];
+    /// Find a file by path, ignoring case and accepting either separator as FSO does,
+    /// so `Data\Tables\Ships.tbl` finds `data/tables/ships.tbl`.
+    pub fn locate<P: Into<VPPath>>(&self, filepath: P) -> Result<VPFile, VPError> {
+        self.locate_parts(filepath.into().parts())
+    }
+
+    fn locate_parts(&self, filepath: &[String]) -> Result<VPFile, VPError> {
+        let (folder, rest) = filepath.split_first().ok_or(VPError::NotFound)?;
         let subentry = self.contents.iter().find(|f| match f {
-            VPEntry::Dir(x) => &x.name == folder,
-            VPEntry::File(x) => &x.name == folder,
+            VPEntry::Dir(x) => names_match(&x.name, folder),
+            VPEntry::File(x) => names_match(&x.name, folder),
         });
 
         match subentry {
             None => Err(VPError::NotFound),
-            Some(VPEntry::Dir(dir)) => dir.locate(&filepath[1..]),
-            Some(VPEntry::File(file)) => Ok(file.clone()),
+            Some(VPEntry::Dir(dir)) => dir.locate_parts(rest),
+            // A file can only be the last part of the path.
+            Some(VPEntry::File(file)) if rest.is_empty() => Ok(file.clone()),
+            Some(VPEntry::File(_)) => Err(VPError::NotFound),
         }
     }
 
-    pub fn flatten(&self) -> Vec<VPFile> {
+    /// Find every file matching a glob pattern such as `data/tables/*.tbm`.
+    /// `*` and `?` match within a single path component, `**` matches any number of directories.
+    /// Like paths, patterns ignore case.
+    /// Returned names are relative to this directory, without its name prefixed.
+    pub fn glob(&self, pattern: &str) -> Vec<VPFile> {
+        let pattern: Vec<&str> = split_components(pattern);
+        self.files()
+            .into_iter()
+            .filter(|f| glob_match(&pattern, &split_components(&f.name)))
+            .collect()
+    }
+
+    /// Add a file to the tree under the directories in `dirpath`,
+    /// creating any directories that don't exist yet.
+    /// Existing names are matched ignoring case, so `Data/a.tbl` goes in the same `data`.
+    pub fn insert(&mut self, dirpath: &[String], file: VPFile) -> Result<(), VPError> {
+        match dirpath.split_first() {
+            None => {
+                let exists = self.contents.iter().any(|e| match e {
+                    VPEntry::Dir(x) => names_match(&x.name, &file.name),
+                    VPEntry::File(x) => names_match(&x.name, &file.name),
+                });
+                if exists {
+                    return Err(VPError::AlreadyExists);
+                }
+                self.contents.push(VPEntry::File(file));
+                Ok(())
+            }
+            Some((folder, rest)) => {
+                let pos = self.contents.iter().position(|e| match e {
+                    VPEntry::Dir(x) => names_match(&x.name, folder),
+                    VPEntry::File(x) => names_match(&x.name, folder),
+                });
+                let pos = match pos {
+                    Some(pos) => pos,
+                    None => {
+                        self.contents.push(VPEntry::Dir(VPDir {
+                            name: folder.clone(),
+                            contents: Vec::new(),
+                        }));
+                        self.contents.len() - 1
+                    }
+                };
+                match &mut self.contents[pos] {
+                    VPEntry::Dir(dir) => dir.insert(rest, file),
+                    // Can't put a file inside a file.
+                    VPEntry::File(_) => Err(VPError::AlreadyExists),
+                }
+            }
+        }
+    }
+
+    /// Sort the contents of this directory and everything under it by name, ignoring case.
+    pub fn sort(&mut self) {
+        self.contents.sort_by_cached_key(|e| match e {
+            VPEntry::Dir(d) => d.name.to_lowercase(),
+            VPEntry::File(f) => f.name.to_lowercase(),
+        });
+        for entry in self.contents.iter_mut() {
+            if let VPEntry::Dir(d) = entry {
+                d.sort();
+            }
+        }
+    }
+
+    /// Every file under this directory, named by its path relative to it.
+    /// Unlike `flatten`, the directory's own name isn't prefixed,
+    /// so for the root of a VP this gives paths like `data/tables/ships.tbl`.
+    pub fn files(&self) -> Vec<VPFile> {
         self.contents
             .iter()
             .flat_map(|e| match e {
                 VPEntry::Dir(d) => d.flatten(),
                 VPEntry::File(f) => vec![f.clone()],
             })
+            .collect()
+    }
+
+    pub fn flatten(&self) -> Vec<VPFile> {
+        self.files()
+            .into_iter()
             .map(|f| VPFile {
                 fileoffset: f.fileoffset,
                 size: f.size,
@@ -74,7 +244,40 @@ impl VPDir {
     }
 }
 
+fn split_components(path: &str) -> Vec<&str> {
+    path.split(['/', '\\']).filter(|c| !c.is_empty()).collect()
+}
+
+fn glob_match(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        // `**` can swallow zero or more directories.
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        Some((component, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                let component: Vec<char> = component.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                component_match(&component, &name) && glob_match(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn component_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| component_match(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && component_match(rest, &name[1..]),
+        Some((c, rest)) => {
+            name.first().is_some_and(|n| n.eq_ignore_ascii_case(c))
+                && component_match(rest, &name[1..])
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VPFile {
     pub fileoffset: u64,
     pub size: u64,
@@ -87,56 +290,56 @@ impl From<VPIndex> for VPFile {
         Self {
             fileoffset: vpi.fileoffset.into(),
             size: vpi.size.into(),
-            name: vpi
-                .name
-                .iter()
-                .take_while(|&&c| c != 0)
-                .map(|&u| u as char)
-                .collect(),
+            name: decode_name(&vpi.name),
             timestamp: vpi.timestamp,
         }
     }
 }
 
-impl From<&mut Iter<'_, VPIndex>> for VPDir {
-    fn from(vvpi: &mut Iter<VPIndex>) -> Self {
+impl VPDir {
+    // `depth` is how many directories deep we are, so we can spot stray or missing `..` entries.
+    fn from_index(vvpi: &mut Iter<VPIndex>, depth: usize) -> Result<Self, VPParseError> {
         let mut vpdir = VPDir::default();
         while let Some(vpi) = vvpi.next() {
-            let vpi_name: String = vpi
-                .name
-                .iter()
-                .take_while(|&&c| c != 0)
-                .map(|&u| u as char)
-                .collect();
+            let vpi_name = decode_name(&vpi.name);
             match vpi.size {
                 // if size is 0, we're defining a directory
-                0 => {
-                    match vpi_name.as_str() {
-                        ".." => break, // End of folder, so return vpdir with full contents vector
-                        &_ => vpdir.contents.push(VPEntry::Dir({
-                            let mut v = VPDir::from(&mut *vvpi);
-                            v.name = vpi_name.to_string();
-                            v
-                        })),
-                    }
-                } //i+1 here as we're iterating from [1..]
+                0 => match vpi_name.as_str() {
+                    // End of folder, so return vpdir with full contents vector
+                    ".." if depth == 0 => return Err(VPParseError::UnbalancedDirs),
+                    ".." => return Ok(vpdir),
+                    &_ => vpdir.contents.push(VPEntry::Dir({
+                        let mut v = VPDir::from_index(vvpi, depth + 1)?;
+                        v.name = vpi_name;
+                        v
+                    })),
+                },
                 _ => vpdir
                     .contents
                     .push(VPEntry::File(VPFile::from(vpi.clone()))),
             }
         }
-        vpdir
+        // The root directory is the only one that isn't closed with a `..`
+        if depth == 0 {
+            Ok(vpdir)
+        } else {
+            Err(VPParseError::UnbalancedDirs)
+        }
     }
 }
 
-impl From<Vec<VPIndex>> for VPDir {
-    fn from(vvpi: Vec<VPIndex>) -> Self {
-        Self::from(vvpi.iter())
+impl TryFrom<&[VPIndex]> for VPDir {
+    type Error = VPParseError;
+
+    fn try_from(vvpi: &[VPIndex]) -> Result<Self, Self::Error> {
+        Self::from_index(&mut vvpi.iter(), 0)
     }
 }
 
-impl From<Iter<'_, VPIndex>> for VPDir {
-    fn from(mut vvpi: Iter<'_, VPIndex>) -> Self {
-        Self::from(&mut vvpi)
+impl TryFrom<Vec<VPIndex>> for VPDir {
+    type Error = VPParseError;
+
+    fn try_from(vvpi: Vec<VPIndex>) -> Result<Self, Self::Error> {
+        Self::try_from(vvpi.as_slice())
     }
 }
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use crate::compression::{LZ4Info, FOOTER_LEN, LZ41_MAGIC};
+use crate::fs::{self, HEADER_LEN, INDEX_ENTRY_LEN};
+use crate::types::{VPFile, VPHeader, VPIndex, VPParseError};
+
+/// Something wrong with a VP that doesn't stop us reading its index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// Index offset points inside the header.
+    IndexInHeader {
+        offset: u64,
+    },
+    /// Bytes after the end of the index that nothing refers to.
+    TrailingData {
+        bytes: u64,
+    },
+    /// A `..` with no directory to close, or directories left open at the end of the index.
+    UnbalancedDirs,
+    /// All 32 bytes of the name are used, so there's no NUL terminator.
+    UnterminatedName {
+        path: String,
+    },
+    /// Same name appears twice in one directory, FSO will only ever read one of them.
+    DuplicatePath {
+        path: String,
+    },
+    /// Entry data isn't entirely between the header and the index.
+    OutOfBounds {
+        path: String,
+        offset: u64,
+        size: u64,
+    },
+    Overlap {
+        path: String,
+        other: String,
+    },
+    /// LZ41 entry whose footer or block offset table doesn't make sense.
+    BadLZ41 {
+        path: String,
+        reason: String,
+    },
+}
+
+impl Problem {
+    /// Short stable identifier for machine readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Problem::IndexInHeader { .. } => "index_in_header",
+            Problem::TrailingData { .. } => "trailing_data",
+            Problem::UnbalancedDirs => "unbalanced_dirs",
+            Problem::UnterminatedName { .. } => "unterminated_name",
+            Problem::DuplicatePath { .. } => "duplicate_path",
+            Problem::OutOfBounds { .. } => "out_of_bounds",
+            Problem::Overlap { .. } => "overlap",
+            Problem::BadLZ41 { .. } => "bad_lz41",
+        }
+    }
+
+    /// Whether reading entries from the VP could give the wrong data.
+    /// The rest are untidy, but FSO and our readers still get the right contents.
+    pub fn corrupts_data(&self) -> bool {
+        matches!(
+            self,
+            Problem::UnbalancedDirs
+                | Problem::UnterminatedName { .. }
+                | Problem::OutOfBounds { .. }
+                | Problem::BadLZ41 { .. }
+        )
+    }
+
+    /// Path of the entry the problem is with, if it's down to a single entry.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Problem::UnterminatedName { path }
+            | Problem::DuplicatePath { path }
+            | Problem::OutOfBounds { path, .. }
+            | Problem::Overlap { path, .. }
+            | Problem::BadLZ41 { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::IndexInHeader { offset } => {
+                write!(f, "index offset {} is inside the header", offset)
+            }
+            Problem::TrailingData { bytes } => {
+                write!(f, "{} bytes of trailing data after the index", bytes)
+            }
+            Problem::UnbalancedDirs => write!(f, "unbalanced directories in index"),
+            Problem::UnterminatedName { path } => write!(f, "{}: name isn't terminated", path),
+            Problem::DuplicatePath { path } => write!(f, "{}: duplicate path", path),
+            Problem::OutOfBounds { path, offset, size } => write!(
+                f,
+                "{}: {} bytes at {} is outside the data section",
+                path, size, offset
+            ),
+            Problem::Overlap { path, other } => write!(f, "{}: overlaps {}", path, other),
+            Problem::BadLZ41 { path, reason } => write!(f, "{}: {}", path, reason),
+        }
+    }
+}
+
+/// Check a VP for problems beyond what stops `fs::index` from reading it.
+/// Errors are for VPs we can't get an index out of at all.
+pub fn validate<T: Read + Seek>(handle: &mut T) -> Result<Vec<Problem>, VPParseError> {
+    let (head, vp_index, file_len) = fs::read_index(handle)?;
+    let (mut problems, files) = check_index(&head, &vp_index, file_len);
+    for file in files.iter() {
+        let mut magic = [0u8; 4];
+        if file.size < magic.len() as u64 {
+            continue;
+        }
+        handle.seek(SeekFrom::Start(file.fileoffset))?;
+        handle.read_exact(&mut magic)?;
+        if &magic != LZ41_MAGIC {
+            continue;
+        }
+        // Footer first, that tells us how big the offset table is.
+        let mut footer = [0u8; FOOTER_LEN];
+        if file.size >= (LZ41_MAGIC.len() + FOOTER_LEN) as u64 {
+            handle.seek(SeekFrom::Start(
+                file.fileoffset + file.size - FOOTER_LEN as u64,
+            ))?;
+            handle.read_exact(&mut footer)?;
+        }
+        let mut table = Vec::new();
+        if let Some(table_start) = table_start(file, &footer) {
+            table = vec![0u8; file.size as usize - FOOTER_LEN - table_start as usize];
+            handle.seek(SeekFrom::Start(file.fileoffset + table_start))?;
+            handle.read_exact(&mut table)?;
+        }
+        problems.extend(check_lz41(file, &footer, &table));
+    }
+    Ok(problems)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn async_validate<T: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: &mut T,
+) -> Result<Vec<Problem>, VPParseError> {
+    let (head, vp_index, file_len) = fs::async_read_index(handle).await?;
+    let (mut problems, files) = check_index(&head, &vp_index, file_len);
+    for file in files.iter() {
+        let mut magic = [0u8; 4];
+        if file.size < magic.len() as u64 {
+            continue;
+        }
+        handle.seek(SeekFrom::Start(file.fileoffset)).await?;
+        handle.read_exact(&mut magic).await?;
+        if &magic != LZ41_MAGIC {
+            continue;
+        }
+        let mut footer = [0u8; FOOTER_LEN];
+        if file.size >= (LZ41_MAGIC.len() + FOOTER_LEN) as u64 {
+            handle
+                .seek(SeekFrom::Start(
+                    file.fileoffset + file.size - FOOTER_LEN as u64,
+                ))
+                .await?;
+            handle.read_exact(&mut footer).await?;
+        }
+        let mut table = Vec::new();
+        if let Some(table_start) = table_start(file, &footer) {
+            table = vec![0u8; file.size as usize - FOOTER_LEN - table_start as usize];
+            handle
+                .seek(SeekFrom::Start(file.fileoffset + table_start))
+                .await?;
+            handle.read_exact(&mut table).await?;
+        }
+        problems.extend(check_lz41(file, &footer, &table));
+    }
+    Ok(problems)
+}
+
+// Everything we can check from the index alone.
+// Also returns the files that are in bounds, so their contents can be checked.
+fn check_index(
+    head: &VPHeader,
+    vp_index: &[VPIndex],
+    file_len: u64,
+) -> (Vec<Problem>, Vec<VPFile>) {
+    let mut problems = Vec::new();
+    let index_offset = u64::from(head.offset);
+    if index_offset < HEADER_LEN as u64 {
+        problems.push(Problem::IndexInHeader {
+            offset: index_offset,
+        });
+    }
+    let index_end = index_offset + u64::from(head.entries) * INDEX_ENTRY_LEN;
+    if index_end < file_len {
+        problems.push(Problem::TrailingData {
+            bytes: file_len - index_end,
+        });
+    }
+
+    // Walk the index keeping track of which directory we're in,
+    // and what's already in each directory on the way down.
+    // Lowercased, as FSO doesn't care about case.
+    let mut dirs: Vec<String> = Vec::new();
+    let mut seen: Vec<HashMap<String, bool>> = vec![HashMap::new()];
+    let mut unbalanced = false;
+    let mut files = Vec::new();
+    for vpi in vp_index {
+        let file = VPFile::from(vpi.clone());
+        if !vpi.name.contains(&0) {
+            problems.push(Problem::UnterminatedName {
+                path: join_path(&dirs, &file.name),
+            });
+        }
+        if vpi.size == 0 && file.name == ".." {
+            if dirs.pop().is_none() {
+                unbalanced = true;
+            } else {
+                seen.pop();
+            }
+            continue;
+        }
+        let path = join_path(&dirs, &file.name);
+        let is_file = vpi.size != 0;
+        // A directory can be opened more than once, but nothing can share a name with a file.
+        let current = seen.last_mut().unwrap();
+        match current.insert(file.name.to_ascii_lowercase(), is_file) {
+            Some(was_file) if was_file || is_file => {
+                problems.push(Problem::DuplicatePath { path: path.clone() })
+            }
+            _ => (),
+        }
+        if !is_file {
+            dirs.push(file.name);
+            seen.push(HashMap::new());
+            continue;
+        }
+        if file.fileoffset < HEADER_LEN as u64 || file.fileoffset + file.size > index_offset {
+            problems.push(Problem::OutOfBounds {
+                path,
+                offset: file.fileoffset,
+                size: file.size,
+            });
+        } else {
+            files.push(VPFile { name: path, ..file });
+        }
+    }
+    if unbalanced || !dirs.is_empty() {
+        problems.push(Problem::UnbalancedDirs);
+    }
+
+    // Overlaps are easy to spot once sorted by offset,
+    // we only need to compare against whichever earlier entry reaches furthest.
+    let mut by_offset: Vec<&VPFile> = files.iter().collect();
+    by_offset.sort_by_key(|f| f.fileoffset);
+    let mut furthest: Option<&VPFile> = None;
+    for file in by_offset {
+        if let Some(prev) = furthest {
+            if file.fileoffset < prev.fileoffset + prev.size {
+                problems.push(Problem::Overlap {
+                    path: file.name.clone(),
+                    other: prev.name.clone(),
+                });
+            }
+            if file.fileoffset + file.size <= prev.fileoffset + prev.size {
+                continue;
+            }
+        }
+        furthest = Some(file);
+    }
+    (problems, files)
+}
+
+fn join_path(dirs: &[String], name: &str) -> String {
+    dirs.iter()
+        .map(String::as_str)
+        .chain(std::iter::once(name))
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+// Where the block offset table of a compressed entry starts, if the footer is sane enough to say.
+fn table_start(file: &VPFile, footer: &[u8; FOOTER_LEN]) -> Option<u64> {
+    let (_, info) = LZ4Info::parse(footer).ok()?;
+    let table_len = info.table_len() as u64;
+    let overhead = (LZ41_MAGIC.len() + FOOTER_LEN) as u64 + table_len;
+    if file.size < overhead {
+        return None;
+    }
+    Some(file.size - FOOTER_LEN as u64 - table_len)
+}
+
+fn check_lz41(file: &VPFile, footer: &[u8; FOOTER_LEN], table: &[u8]) -> Option<Problem> {
+    let bad = |reason: String| Problem::BadLZ41 {
+        path: file.name.clone(),
+        reason,
+    };
+    if file.size < (LZ41_MAGIC.len() + FOOTER_LEN) as u64 {
+        return Some(bad("LZ41 entry too short for footer".to_string()));
+    }
+    let (_, info) = LZ4Info::parse(footer).ok()?;
+    match info.parse_offsets(table, file.size as usize) {
+        Ok(_) => None,
+        Err(e) => Some(bad(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{encode_name, header_bytes, index_entry_bytes};
+    use std::io::Cursor;
+
+    // Hand build a VP, with data and index entries as given.
+    fn raw_vp(data: &[u8], entries: &[(u32, u32, [u8; 32])]) -> Vec<u8> {
+        let mut out = header_bytes(&VPHeader {
+            version: 2,
+            offset: (HEADER_LEN + data.len()) as u32,
+            entries: entries.len() as u32,
+        })
+        .to_vec();
+        out.extend(data);
+        for &(fileoffset, size, name) in entries {
+            out.extend(index_entry_bytes(&VPIndex {
+                fileoffset,
+                size,
+                name,
+                timestamp: 0,
+            }));
+        }
+        out
+    }
+
+    fn name(name: &str) -> [u8; 32] {
+        encode_name(name).unwrap()
+    }
+
+    #[test]
+    fn valid_vp() {
+        let mut vp = std::fs::File::open("./test_files/mv_radaricons.vp").unwrap();
+        assert_eq!(validate(&mut vp).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn index_problems() {
+        let vp = raw_vp(
+            b"abcdefgh",
+            &[
+                (0, 0, name("data")),
+                (16, 4, name("a.tbl")),
+                (18, 4, name("b.tbl")),
+                (20, 4, name("A.TBL")),
+                (20, 400, name("c.tbl")),
+                (0, 0, name("..")),
+                (0, 0, name("..")),
+                (16, 2, [b'x'; 32]),
+            ],
+        );
+        let problems = validate(&mut Cursor::new(vp)).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                Problem::DuplicatePath {
+                    path: "data/A.TBL".to_string()
+                },
+                Problem::OutOfBounds {
+                    path: "data/c.tbl".to_string(),
+                    offset: 20,
+                    size: 400
+                },
+                Problem::UnterminatedName {
+                    path: "x".repeat(32)
+                },
+                Problem::UnbalancedDirs,
+                Problem::Overlap {
+                    path: "x".repeat(32),
+                    other: "data/a.tbl".to_string()
+                },
+                Problem::Overlap {
+                    path: "data/b.tbl".to_string(),
+                    other: "data/a.tbl".to_string()
+                },
+                Problem::Overlap {
+                    path: "data/A.TBL".to_string(),
+                    other: "data/b.tbl".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn header_problems() {
+        let mut vp = raw_vp(b"abcd", &[(16, 4, name("a.tbl"))]);
+        vp.extend(b"junk");
+        let problems = validate(&mut Cursor::new(vp)).unwrap();
+        assert_eq!(problems, vec![Problem::TrailingData { bytes: 4 }]);
+        assert!(!problems[0].corrupts_data());
+    }
+
+    #[test]
+    fn lz41_problems() {
+        let raw = std::fs::read("./test_files/radar-asteroid.dds").unwrap();
+        let good = crate::compression::maybe_compress("good.dds", raw);
+        // Footer claims a file size that would need more blocks than there are offsets for.
+        let mut bad_size = good.clone();
+        let len = bad_size.len();
+        bad_size[len - 8..len - 4].copy_from_slice(&200000u32.to_le_bytes());
+        let short = b"LZ41abcd".to_vec();
+
+        let data = [good.clone(), bad_size.clone(), short.clone()].concat();
+        let vp = raw_vp(
+            &data,
+            &[
+                (16, good.len() as u32, name("good.dds")),
+                (
+                    16 + good.len() as u32,
+                    bad_size.len() as u32,
+                    name("bad.dds"),
+                ),
+                (
+                    16 + (good.len() + bad_size.len()) as u32,
+                    short.len() as u32,
+                    name("short.dds"),
+                ),
+            ],
+        );
+        let problems = validate(&mut Cursor::new(vp)).unwrap();
+        let paths: Vec<_> = problems.iter().map(|p| (p.kind(), p.path())).collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("bad_lz41", Some("bad.dds")),
+                ("bad_lz41", Some("short.dds"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,664 @@
+use crate::compression::{maybe_compress, maybe_decompress};
+use crate::fs::{time_to_timestamp, HEADER_LEN, INDEX_ENTRY_LEN, VP_VERSION};
+pub use crate::path::encode_name;
+use crate::path::VPPath;
+use crate::types::{VPDir, VPEntry, VPError, VPFile, VPHeader, VPIndex};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// Options for packing a directory into a VP.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PackOptions {
+    /// LZ41 compress entries where it saves space.
+    pub compress: bool,
+    /// Timestamp for every file instead of its modification time,
+    /// so packing the same files always produces the same VP.
+    pub timestamp: Option<u32>,
+}
+
+/// Options for rewriting an existing VP with `repack`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RepackOptions {
+    /// Sort the contents of each directory by name.
+    pub sort: bool,
+    /// `Some(true)` (re)compresses every entry where it saves space,
+    /// `Some(false)` decompresses everything and `None` leaves entries as they are.
+    pub compress: Option<bool>,
+}
+
+/// What `repack` did.
+#[derive(Clone, Debug, Default)]
+pub struct RepackStats {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub files: usize,
+    /// Earlier entries dropped because a later one has the same path.
+    pub duplicates: Vec<String>,
+}
+
+impl RepackStats {
+    /// Bytes saved, negative if the VP got bigger, i.e. by decompressing it.
+    pub fn reclaimed(&self) -> i64 {
+        self.old_size as i64 - self.new_size as i64
+    }
+}
+
+/// Writes a VP archive to a seekable output.
+///
+/// File data is written as it's added, and the index is written on `finish()`.
+/// The header can only be filled in once we know where the index starts,
+/// so a placeholder is written first and patched at the end.
+pub struct VPWriter<W: Write + Seek> {
+    inner: W,
+    index: VPDir,
+    pos: u64,
+    compress: bool,
+}
+
+impl<W: Write + Seek> VPWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        inner.write_all(&[0u8; HEADER_LEN])?;
+        Ok(Self {
+            inner,
+            index: VPDir::default(),
+            pos: HEADER_LEN as u64,
+            compress: false,
+        })
+    }
+
+    /// LZ41 compress files added from now on, where FSO supports it and it saves space.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Add a file at `path` inside the VP, i.e. `data/tables/ships.tbl`.
+    pub fn add_file(&mut self, path: &str, data: &[u8], timestamp: u32) -> io::Result<()> {
+        self.add_reader(path, &mut &data[..], timestamp)
+    }
+
+    /// Like `add_file`, but streams the contents from a reader.
+    pub fn add_reader<R: Read>(
+        &mut self,
+        path: &str,
+        reader: &mut R,
+        timestamp: u32,
+    ) -> io::Result<()> {
+        let (dirs, name) = split_vp_path(path)?;
+        let size = if self.compress {
+            // Need the whole file in memory to know if compressing it is worthwhile.
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf)?;
+            let buf = maybe_compress(path, buf);
+            self.inner.write_all(&buf)?;
+            buf.len() as u64
+        } else {
+            io::copy(reader, &mut self.inner)?
+        };
+        let file = VPFile {
+            fileoffset: self.pos,
+            size,
+            name,
+            timestamp,
+        };
+        match check_file(&file).and_then(|_| insert(&mut self.index, &dirs, file)) {
+            Ok(()) => {
+                self.pos += size;
+                Ok(())
+            }
+            Err(e) => {
+                // Rewind so the next file overwrites whatever we just wrote.
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Write out the index and header, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let (head, index) = build_index(&self.index, self.pos)?;
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        self.inner.write_all(&index)?;
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header_bytes(&head))?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Async version of `VPWriter`.
+#[cfg(feature = "tokio")]
+pub struct AsyncVPWriter<W: AsyncWrite + AsyncSeek + Unpin> {
+    inner: W,
+    index: VPDir,
+    pos: u64,
+    compress: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl<W: AsyncWrite + AsyncSeek + Unpin> AsyncVPWriter<W> {
+    pub async fn new(mut inner: W) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(0)).await?;
+        inner.write_all(&[0u8; HEADER_LEN]).await?;
+        Ok(Self {
+            inner,
+            index: VPDir::default(),
+            pos: HEADER_LEN as u64,
+            compress: false,
+        })
+    }
+
+    /// LZ41 compress files added from now on, where FSO supports it and it saves space.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    pub async fn add_file(&mut self, path: &str, data: &[u8], timestamp: u32) -> io::Result<()> {
+        self.add_reader(path, &mut &data[..], timestamp).await
+    }
+
+    pub async fn add_reader<R: AsyncRead + Unpin>(
+        &mut self,
+        path: &str,
+        reader: &mut R,
+        timestamp: u32,
+    ) -> io::Result<()> {
+        let (dirs, name) = split_vp_path(path)?;
+        let size = if self.compress {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await?;
+            let buf = maybe_compress(path, buf);
+            self.inner.write_all(&buf).await?;
+            buf.len() as u64
+        } else {
+            tokio::io::copy(reader, &mut self.inner).await?
+        };
+        let file = VPFile {
+            fileoffset: self.pos,
+            size,
+            name,
+            timestamp,
+        };
+        match check_file(&file).and_then(|_| insert(&mut self.index, &dirs, file)) {
+            Ok(()) => {
+                self.pos += size;
+                Ok(())
+            }
+            Err(e) => {
+                self.inner.seek(SeekFrom::Start(self.pos)).await?;
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn finish(mut self) -> io::Result<W> {
+        let (head, index) = build_index(&self.index, self.pos)?;
+        self.inner.seek(SeekFrom::Start(self.pos)).await?;
+        self.inner.write_all(&index).await?;
+        self.inner.seek(SeekFrom::Start(0)).await?;
+        self.inner.write_all(&header_bytes(&head)).await?;
+        self.inner.seek(SeekFrom::End(0)).await?;
+        self.inner.flush().await?;
+        Ok(self.inner)
+    }
+}
+
+/// Pack the contents of `src` into a VP.
+/// `src` is treated as the VP root, so it should usually contain a `data` folder.
+pub fn write_dir<W: Write + Seek>(
+    src: impl AsRef<Path>,
+    out: W,
+    opts: &PackOptions,
+) -> io::Result<W> {
+    let mut writer = VPWriter::new(out)?;
+    writer.set_compression(opts.compress);
+    for (vp_path, fs_path) in dir_entries(src.as_ref())? {
+        let mut file = File::open(fs_path)?;
+        let timestamp = match opts.timestamp {
+            Some(timestamp) => timestamp,
+            None => time_to_timestamp(file.metadata()?.modified()?),
+        };
+        writer.add_reader(&vp_path, &mut file, timestamp)?;
+    }
+    writer.finish()
+}
+
+#[cfg(feature = "tokio")]
+pub async fn async_write_dir<W: AsyncWrite + AsyncSeek + Unpin>(
+    src: impl AsRef<Path>,
+    out: W,
+    opts: &PackOptions,
+) -> io::Result<W> {
+    let src = src.as_ref().to_path_buf();
+    let entries = tokio::task::spawn_blocking(move || dir_entries(&src)).await??;
+    let mut writer = AsyncVPWriter::new(out).await?;
+    writer.set_compression(opts.compress);
+    for (vp_path, fs_path) in entries {
+        let mut file = tokio::fs::File::open(fs_path).await?;
+        let timestamp = match opts.timestamp {
+            Some(timestamp) => timestamp,
+            None => time_to_timestamp(file.metadata().await?.modified()?),
+        };
+        writer.add_reader(&vp_path, &mut file, timestamp).await?;
+    }
+    writer.finish().await
+}
+
+/// Rewrite a VP so its data is contiguous and in index order,
+/// dropping gaps, empty directories and entries shadowed by a later one with the same path.
+pub fn repack<R: Read + Seek, W: Write + Seek>(
+    src: &mut R,
+    out: W,
+    opts: &RepackOptions,
+) -> io::Result<(W, RepackStats)> {
+    let mut index = crate::fs::index(src)?;
+    let mut stats = RepackStats {
+        old_size: src.seek(SeekFrom::End(0))?,
+        ..Default::default()
+    };
+    if opts.sort {
+        index.sort();
+    }
+    let files = index.files();
+    // Hand edited VPs can end up with the same path twice,
+    // whichever was added to the index last is the one that was meant to replace the other.
+    // FSO ignores case, so we do too.
+    let last: HashMap<VPPath, usize> = files
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (VPPath::from(&f.name), i))
+        .collect();
+
+    let mut writer = VPWriter::new(out)?;
+    for (i, file) in files.iter().enumerate() {
+        if last[&VPPath::from(&file.name)] != i {
+            stats.duplicates.push(file.name.clone());
+            continue;
+        }
+        src.seek(SeekFrom::Start(file.fileoffset))?;
+        let mut buf = vec![0u8; file.size as usize];
+        src.read_exact(&mut buf)?;
+        let buf = match opts.compress {
+            None => buf,
+            Some(false) => maybe_decompress(buf)?,
+            Some(true) => maybe_compress(&file.name, maybe_decompress(buf)?),
+        };
+        writer.add_file(&file.name, &buf, file.timestamp)?;
+        stats.files += 1;
+    }
+    let mut out = writer.finish()?;
+    stats.new_size = out.seek(SeekFrom::End(0))?;
+    Ok((out, stats))
+}
+
+/// Walk a directory, returning (VP path, filesystem path) pairs in a stable order.
+pub(crate) fn dir_entries(root: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut out = Vec::new();
+    let mut stack = vec![(String::new(), root.to_path_buf())];
+    while let Some((prefix, dir)) = stack.pop() {
+        let entries = fs::read_dir(&dir)?.collect::<io::Result<Vec<_>>>()?;
+        for entry in entries {
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "non-unicode filename"))?;
+            let vp_path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                stack.push((vp_path, entry.path()));
+            } else if meta.len() > 0 {
+                // A zero size entry is how VPs mark directories, so empty files can't be stored.
+                out.push((vp_path, entry.path()));
+            }
+        }
+    }
+    // Keep files grouped by directory, same as they'll appear in the index.
+    out.sort_by(|a, b| a.0.split('/').cmp(b.0.split('/')));
+    Ok(out)
+}
+
+fn split_vp_path(path: &str) -> io::Result<(Vec<String>, String)> {
+    let mut parts: Vec<String> = path
+        .split(['/', '\\'])
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect();
+    let name = parts
+        .pop()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty VP path"))?;
+    Ok((parts, name))
+}
+
+fn check_file(file: &VPFile) -> io::Result<()> {
+    if file.size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty files can't be stored in a VP",
+        ));
+    }
+    if file.fileoffset + file.size > u32::MAX.into() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "VP archives are limited to 4GiB",
+        ));
+    }
+    encode_name(&file.name).map(|_| ())
+}
+
+fn insert(index: &mut VPDir, dirs: &[String], file: VPFile) -> io::Result<()> {
+    for dir in dirs.iter() {
+        encode_name(dir)?;
+    }
+    index.insert(dirs, file).map_err(|e| match e {
+        VPError::AlreadyExists => {
+            io::Error::new(io::ErrorKind::AlreadyExists, "duplicate path in VP")
+        }
+        VPError::NotFound => io::Error::from(io::ErrorKind::NotFound),
+    })
+}
+
+pub fn header_bytes(head: &VPHeader) -> [u8; HEADER_LEN] {
+    let mut buf = [0u8; HEADER_LEN];
+    buf[..4].copy_from_slice(b"VPVP");
+    buf[4..8].copy_from_slice(&head.version.to_le_bytes());
+    buf[8..12].copy_from_slice(&head.offset.to_le_bytes());
+    buf[12..].copy_from_slice(&head.entries.to_le_bytes());
+    buf
+}
+
+pub fn index_entry_bytes(index: &VPIndex) -> [u8; INDEX_ENTRY_LEN as usize] {
+    let mut buf = [0u8; INDEX_ENTRY_LEN as usize];
+    buf[..4].copy_from_slice(&index.fileoffset.to_le_bytes());
+    buf[4..8].copy_from_slice(&index.size.to_le_bytes());
+    buf[8..40].copy_from_slice(&index.name);
+    buf[40..].copy_from_slice(&index.timestamp.to_le_bytes());
+    buf
+}
+
+/// Generate the header and serialised index for a directory tree,
+/// where file data finishes at `data_end`.
+pub fn build_index(root: &VPDir, data_end: u64) -> io::Result<(VPHeader, Vec<u8>)> {
+    let too_big = |_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "VP archives are limited to 4GiB",
+        )
+    };
+    let mut entries = Vec::<VPIndex>::new();
+    let mut cursor = HEADER_LEN as u32;
+    push_entries(root, &mut cursor, &mut entries)?;
+    let head = VPHeader {
+        version: VP_VERSION,
+        offset: data_end.try_into().map_err(too_big)?,
+        entries: entries.len().try_into().map_err(too_big)?,
+    };
+    let index = entries.iter().flat_map(index_entry_bytes).collect();
+    Ok((head, index))
+}
+
+// Directories are written as an entry with size 0 and the directory's name,
+// followed by their contents, and closed with a size 0 entry called "..".
+fn push_entries(dir: &VPDir, cursor: &mut u32, out: &mut Vec<VPIndex>) -> io::Result<()> {
+    let too_big = |_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "VP archives are limited to 4GiB",
+        )
+    };
+    for entry in dir.contents.iter() {
+        match entry {
+            VPEntry::File(f) => {
+                let fileoffset: u32 = f.fileoffset.try_into().map_err(too_big)?;
+                let size: u32 = f.size.try_into().map_err(too_big)?;
+                out.push(VPIndex {
+                    fileoffset,
+                    size,
+                    name: encode_name(&f.name)?,
+                    timestamp: f.timestamp,
+                });
+                *cursor = fileoffset + size;
+            }
+            VPEntry::Dir(d) => {
+                out.push(VPIndex {
+                    fileoffset: *cursor,
+                    size: 0,
+                    name: encode_name(&d.name)?,
+                    timestamp: 0,
+                });
+                push_entries(d, cursor, out)?;
+                out.push(VPIndex {
+                    fileoffset: *cursor,
+                    size: 0,
+                    name: encode_name("..")?,
+                    timestamp: 0,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs;
+    use std::io::Cursor;
+
+    #[test]
+    fn roundtrip_files() {
+        let mut writer = VPWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer
+            .add_file("data/tables/ships.tbl", b"#Ship Classes", 1)
+            .unwrap();
+        writer
+            .add_file("data/effects/fire.dds", b"DDS ", 2)
+            .unwrap();
+        writer
+            .add_file("data/tables/weapons.tbl", b"#Primary Weapons", 3)
+            .unwrap();
+        let mut out = writer.finish().unwrap();
+
+        let index = fs::index(&mut out).unwrap();
+        let path = ["data", "tables", "weapons.tbl"].map(String::from);
+        let entry = index.locate(&path).unwrap();
+        let data = out.get_ref();
+        let start = entry.fileoffset as usize;
+        assert_eq!(
+            &data[start..start + entry.size as usize],
+            b"#Primary Weapons"
+        );
+        assert_eq!(entry.timestamp, 3);
+        assert_eq!(index.flatten().len(), 3);
+    }
+
+    #[test]
+    fn reject_duplicates() {
+        let mut writer = VPWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.add_file("data/a.tbl", b"first", 0).unwrap();
+        assert!(writer.add_file("data/a.tbl", b"second", 0).is_err());
+        let mut out = writer.finish().unwrap();
+        let index = fs::index(&mut out).unwrap();
+        let entry = index.locate(&["data", "a.tbl"].map(String::from)).unwrap();
+        // The rejected file's data shouldn't take up space.
+        assert_eq!(
+            out.get_ref().len() as u64,
+            HEADER_LEN as u64 + entry.size + 3 * INDEX_ENTRY_LEN
+        );
+    }
+
+    #[test]
+    fn compressed_entries() {
+        let tbl = "$Name: GTF Ulysses\n".repeat(100).into_bytes();
+        let mut writer = VPWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.set_compression(true);
+        writer.add_file("data/tables/ships.tbl", &tbl, 0).unwrap();
+        writer.add_file("data/music/theme.ogg", &tbl, 0).unwrap();
+        let mut out = writer.finish().unwrap();
+        let index = fs::index(&mut out).unwrap();
+        let data = out.get_ref();
+        let read = |path: [&str; 3]| {
+            let entry = index.locate(&path.map(String::from)).unwrap();
+            let start = entry.fileoffset as usize;
+            data[start..start + entry.size as usize].to_vec()
+        };
+        let ships = read(["data", "tables", "ships.tbl"]);
+        assert!(crate::compression::is_compressed(&ships));
+        assert_eq!(crate::compression::maybe_decompress(ships).unwrap(), tbl);
+        assert_eq!(read(["data", "music", "theme.ogg"]), tbl);
+    }
+
+    #[test]
+    fn rewrite_vp() {
+        // Unpack mv_radaricons and repack it, the index should come out identical.
+        let orig = std::fs::read("./test_files/mv_radaricons.vp").unwrap();
+        let mut cursor = Cursor::new(orig.clone());
+        let index = fs::index(&mut cursor).unwrap();
+        let mut writer = VPWriter::new(Cursor::new(Vec::new())).unwrap();
+        for file in index.flatten() {
+            let start = file.fileoffset as usize;
+            let data = &orig[start..start + file.size as usize];
+            writer.add_file(&file.name, data, file.timestamp).unwrap();
+        }
+        let mut out = writer.finish().unwrap();
+        let new_index = fs::index(&mut out).unwrap();
+        let old_files = index.flatten();
+        let new_files = new_index.flatten();
+        assert_eq!(old_files.len(), new_files.len());
+        for (old, new) in old_files.iter().zip(new_files.iter()) {
+            assert_eq!(old.name, new.name);
+            assert_eq!(old.size, new.size);
+            assert_eq!(old.timestamp, new.timestamp);
+        }
+        // Radaricons has no gaps between files, so should be byte for byte identical
+        // apart from directory timestamps, which are garbage in the original.
+        assert_eq!(orig.len(), out.get_ref().len());
+    }
+
+    // A hand edited VP, with junk between entries and a replaced table.
+    #[test]
+    fn pack_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = dir.path();
+        std::fs::create_dir_all(tmp.join("data/tables")).unwrap();
+        let tbl = tmp.join("data/tables/ships.tbl");
+        std::fs::write(&tbl, b"#Ship Classes").unwrap();
+        File::options()
+            .write(true)
+            .open(&tbl)
+            .unwrap()
+            .set_modified(fs::timestamp_to_time(1_000_000_000))
+            .unwrap();
+
+        let path = ["data", "tables", "ships.tbl"].map(String::from);
+        let mut out = write_dir(tmp, Cursor::new(Vec::new()), &PackOptions::default()).unwrap();
+        let entry = fs::index(&mut out).unwrap().locate(&path).unwrap();
+        assert_eq!(entry.timestamp, 1_000_000_000);
+
+        let opts = PackOptions {
+            timestamp: Some(42),
+            ..Default::default()
+        };
+        let mut out = write_dir(tmp, Cursor::new(Vec::new()), &opts).unwrap();
+        let entry = fs::index(&mut out).unwrap().locate(&path).unwrap();
+        assert_eq!(entry.timestamp, 42);
+    }
+
+    fn messy_vp() -> Vec<u8> {
+        let entries = [
+            (0, 0, "data"),
+            (20, 4, "a.tbl"),
+            (28, 4, "b.tbl"),
+            (32, 4, "A.TBL"),
+            (0, 0, ".."),
+        ];
+        let mut out = header_bytes(&VPHeader {
+            version: VP_VERSION,
+            offset: 36,
+            entries: entries.len() as u32,
+        })
+        .to_vec();
+        out.extend(b"junkAAAAjunkBBBBCCCC");
+        for (fileoffset, size, name) in entries {
+            out.extend(index_entry_bytes(&VPIndex {
+                fileoffset,
+                size,
+                name: encode_name(name).unwrap(),
+                timestamp: 0,
+            }));
+        }
+        out
+    }
+
+    #[test]
+    fn repack_gaps() {
+        let mut src = Cursor::new(messy_vp());
+        let opts = RepackOptions::default();
+        let (mut out, stats) = repack(&mut src, Cursor::new(Vec::new()), &opts).unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.duplicates, vec!["data/a.tbl".to_string()]);
+        // Header, 8 bytes of data and 4 index entries.
+        assert_eq!(stats.new_size, 16 + 8 + 4 * 44);
+        assert_eq!(stats.reclaimed(), 20 - 8 + 44);
+        assert_eq!(&out.get_ref()[16..24], b"BBBBCCCC");
+        let names: Vec<String> = fs::index(&mut out)
+            .unwrap()
+            .flatten()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["/data/b.tbl", "/data/A.TBL"]);
+
+        let opts = RepackOptions {
+            sort: true,
+            ..Default::default()
+        };
+        let (out, _) = repack(&mut src, Cursor::new(Vec::new()), &opts).unwrap();
+        assert_eq!(&out.get_ref()[16..24], b"CCCCBBBB");
+    }
+
+    #[test]
+    fn repack_compression() {
+        let orig = std::fs::read("./test_files/mv_radaricons.vp").unwrap();
+        let mut src = Cursor::new(orig.clone());
+        let opts = RepackOptions {
+            compress: Some(true),
+            ..Default::default()
+        };
+        let (out, stats) = repack(&mut src, Cursor::new(Vec::new()), &opts).unwrap();
+        assert!(stats.reclaimed() > 0);
+        assert!(crate::validate::validate(&mut out.clone())
+            .unwrap()
+            .is_empty());
+
+        // And back again.
+        let opts = RepackOptions {
+            compress: Some(false),
+            ..Default::default()
+        };
+        let (mut out, stats) = repack(&mut out.clone(), Cursor::new(Vec::new()), &opts).unwrap();
+        assert!(stats.reclaimed() < 0);
+        let index = fs::index(&mut out).unwrap();
+        for file in index.flatten() {
+            let path: Vec<String> = file.name[1..].split('/').map(String::from).collect();
+            let before = fs::index(&mut src).unwrap().locate(&path).unwrap();
+            let start = file.fileoffset as usize;
+            let old_start = before.fileoffset as usize;
+            assert_eq!(
+                &out.get_ref()[start..start + file.size as usize],
+                &orig[old_start..old_start + before.size as usize]
+            );
+        }
+    }
+}